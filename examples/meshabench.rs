@@ -33,7 +33,7 @@ impl BenchTarget for meshanina::Mapping {
     }
 
     fn insert(&self, k: [u8; 32], v: &[u8]) {
-        self.insert(k, v)
+        self.insert(k, v).unwrap()
     }
 
     fn get(&self, k: [u8; 32]) -> Option<Vec<u8>> {
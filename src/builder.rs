@@ -0,0 +1,170 @@
+use std::{
+    borrow::Cow,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use arrayref::array_ref;
+use fs2::FileExt;
+use rustc_hash::FxHashSet;
+
+use crate::{
+    merkle::{leaf_hash, node_hash},
+    record::{Codec, Encoding, Record, RecordPtr},
+    segment::{random_divider, reserved_region},
+};
+
+/// Bulk-loads a brand-new database file bottom-up.
+///
+/// Data records are appended as they come in, and only their keys and offsets are kept in memory. On [Builder::finish], the keys are partitioned by HAMT path and every HAMT node is written exactly once, followed by a single root. The result is the most compact file that can represent the given set of key-value pairs.
+pub struct Builder {
+    writer: BufWriter<std::fs::File>,
+    divider: u128,
+    codec: Codec,
+    ptr: u64,
+    seen: FxHashSet<[u8; 32]>,
+    leaves: Vec<([u8; 32], u64)>,
+}
+
+impl Builder {
+    /// Creates a builder that writes to a new file. Fails if the file already exists.
+    pub fn create(fname: impl AsRef<Path>) -> std::io::Result<Self> {
+        Self::create_with(fname, &Encoding::default())
+    }
+
+    /// Like [Builder::create], but encodes records the given way, so that compaction keeps values compressed and encrypted, and roots authenticated.
+    pub(crate) fn create_with(
+        fname: impl AsRef<Path>,
+        encoding: &Encoding,
+    ) -> std::io::Result<Self> {
+        let mut handle = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(fname)?;
+        handle.try_lock_exclusive()?;
+        let divider = random_divider();
+        let codec = Codec::new(encoding, divider);
+        handle.write_all(&reserved_region(divider, &codec))?;
+        Ok(Self {
+            writer: BufWriter::new(handle),
+            divider,
+            codec,
+            ptr: 4096,
+            seen: FxHashSet::default(),
+            leaves: vec![],
+        })
+    }
+
+    /// Builds a new database file from an iterator of key-value pairs in one go.
+    pub fn build<V: AsRef<[u8]>>(
+        fname: impl AsRef<Path>,
+        kvs: impl IntoIterator<Item = ([u8; 32], V)>,
+    ) -> std::io::Result<()> {
+        let mut builder = Self::create(fname)?;
+        for (k, v) in kvs {
+            builder.insert(k, v.as_ref())?;
+        }
+        builder.finish()
+    }
+
+    /// Appends a key-value pair. Does nothing if the key was already inserted.
+    pub fn insert(&mut self, key: [u8; 32], value: &[u8]) -> std::io::Result<()> {
+        if self.seen.insert(key) {
+            let posn =
+                self.write_record(&Record::Data(key, Cow::Borrowed(value)), &leaf_hash(&key))?;
+            self.leaves.push((key, posn));
+        }
+        Ok(())
+    }
+
+    /// Writes out the HAMT and its root, then syncs the file to disk.
+    pub fn finish(mut self) -> std::io::Result<()> {
+        let mut leaves = std::mem::take(&mut self.leaves);
+        self.write_subtree(0, &mut leaves, true)?;
+        self.writer.into_inner()?.sync_all()
+    }
+
+    /// Recursively writes the HAMT node covering the given leaves, which all share the same path up to the given depth. Returns the offset of the node and its Merkle hash.
+    fn write_subtree(
+        &mut self,
+        depth: usize,
+        leaves: &mut [([u8; 32], u64)],
+        is_root: bool,
+    ) -> std::io::Result<(u64, [u8; 32])> {
+        if depth * 6 >= 128 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "keys collide in their first 128 bits",
+            ));
+        }
+        let hindex = |key: &[u8; 32]| {
+            ((u128::from_le_bytes(*array_ref![key, 0, 16]) >> (6 * depth)) & 0b111111) as u32
+        };
+        leaves.sort_unstable_by_key(|(k, _)| hindex(k));
+        let mut bitmap = 0u64;
+        let mut ptrs = vec![];
+        let mut hashes = vec![];
+        for run in leaves.chunk_by_mut(|a, b| hindex(&a.0) == hindex(&b.0)) {
+            bitmap |= 1 << hindex(&run[0].0);
+            let (ptr, hash) = if run.len() == 1 {
+                (run[0].1, leaf_hash(&run[0].0))
+            } else {
+                self.write_subtree(depth + 1, run, false)?
+            };
+            ptrs.push(RecordPtr::OnDisk(ptr));
+            hashes.push(hash);
+        }
+        let hash = node_hash(bitmap, hashes);
+        let ptr = self.write_record(&Record::HamtNode(is_root, bitmap, ptrs), &hash)?;
+        Ok((ptr, hash))
+    }
+
+    /// Appends a record, and returns its offset. The Merkle hash is only used for authenticated roots.
+    fn write_record(&mut self, record: &Record, merkle: &[u8; 32]) -> std::io::Result<u64> {
+        let curr_posn = self.ptr;
+        self.ptr += match self.codec.root_key() {
+            Some(root_key) if record.is_root() => {
+                record
+                    .write_authenticated_root(
+                        self.divider,
+                        root_key,
+                        None,
+                        merkle,
+                        &mut self.writer,
+                    )?
+                    .0
+            }
+            _ => record.write_bytes(self.divider, &self.codec, &mut self.writer)?,
+        } as u64;
+        Ok(curr_posn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Mapping;
+
+    use super::*;
+
+    #[test]
+    fn build_simple() {
+        let dir = tempfile::tempdir().unwrap();
+        let fname = dir.path().join("built.db");
+        let kvs = (0u64..1000).chain(0..10).map(|ctr| {
+            (
+                *blake3::hash(&ctr.to_le_bytes()).as_bytes(),
+                ctr.to_le_bytes(),
+            )
+        });
+        Builder::build(&fname, kvs).unwrap();
+        let db = Mapping::open(&fname).unwrap();
+        for ctr in 0u64..1000 {
+            let b = db
+                .get(*blake3::hash(&ctr.to_le_bytes()).as_bytes())
+                .unwrap();
+            assert_eq!(&b[..], &ctr.to_le_bytes());
+        }
+        assert!(db.get([0u8; 32]).is_none());
+    }
+}
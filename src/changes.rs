@@ -0,0 +1,112 @@
+use bytes::Bytes;
+
+use crate::{Mapping, record::Record};
+
+/// A feed of the data records appended to the log, in the order they were written. See [Mapping::changes_since].
+pub struct Changes<'a> {
+    mapping: &'a Mapping,
+    cursor: u64,
+}
+
+impl Changes<'_> {
+    /// The position in the log up to which records have been read. Passing it to [Mapping::changes_since] later picks up right where this feed left off.
+    pub fn cursor(&self) -> u64 {
+        self.cursor
+    }
+}
+
+impl Iterator for Changes<'_> {
+    type Item = ([u8; 32], Bytes);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // the lock is only held while looking for the next record, so writers are never kept waiting for long
+        let inner = self.mapping.inner.read();
+        // a value still being streamed in will show up where it was reserved, so the cursor waits there for it
+        let limit = inner.streaming_from().unwrap_or(u64::MAX);
+        for (ptr, len, record) in inner.scan(self.cursor) {
+            if ptr >= limit {
+                break;
+            }
+            self.cursor = ptr + len as u64;
+            if let Record::Data(key, value) = record {
+                return Some((key, inner.to_bytes(&value)));
+            }
+        }
+        None
+    }
+}
+
+impl Mapping {
+    /// Iterates over the key-value pairs whose data records were appended to the log after the given position, in log order. Position 0 is the start of the log. Inserted pairs only reach the log when they are flushed, in HAMT order within each flush, except for streamed ones, which are written where they were reserved. Records after a value that is still being streamed in are held back until it is finished, so that resuming from [Changes::cursor] never misses it. Once the iterator runs dry, [Changes::cursor] is the position to resume from later, so that an indexer can tail inserts without rescanning the whole database.
+    ///
+    /// This is a feed of the log, not of the HAMT: it includes pairs that were removed since, and pairs copied to the end of the log by [Mapping::drop_segment] show up again, while purged records are skipped.
+    pub fn changes_since(&self, cursor: u64) -> Changes<'_> {
+        Changes {
+            mapping: self,
+            cursor,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changes_resume() {
+        let dir = tempfile::tempdir().unwrap();
+        let tab = crate::Options::default()
+            .segment_size(10_000)
+            .open_dir(dir.path())
+            .unwrap();
+        let first = (0..200u32)
+            .map(|i| tab.put(&i.to_le_bytes()))
+            .collect::<Vec<_>>();
+        tab.flush();
+        assert!(tab.segments().len() > 1);
+
+        let mut feed = tab.changes_since(0);
+        let mut seen = feed.by_ref().map(|(k, _)| k).collect::<Vec<_>>();
+        seen.sort_unstable();
+        let mut first = first;
+        first.sort_unstable();
+        assert_eq!(seen, first);
+        let cursor = feed.cursor();
+        assert_eq!(tab.changes_since(cursor).count(), 0);
+
+        let later = tab.put(b"later");
+        tab.flush();
+        let changes = tab.changes_since(cursor).collect::<Vec<_>>();
+        assert_eq!(changes, vec![(later, Bytes::from_static(b"later"))]);
+
+        // a value that is streamed in across a flush is not skipped over
+        let streamed = *blake3::hash(b"streamed").as_bytes();
+        let mut reservation = tab
+            .inner
+            .write()
+            .reserve_stream(streamed, 8)
+            .unwrap()
+            .unwrap();
+        tab.inner
+            .write()
+            .write_stream(&mut reservation, b"streamed")
+            .unwrap();
+        let flushed = tab.put(b"flushed meanwhile");
+        tab.flush();
+        let mut feed = tab.changes_since(cursor);
+        assert_eq!(
+            feed.by_ref().map(|(k, _)| k).collect::<Vec<_>>(),
+            vec![later]
+        );
+        let cursor = feed.cursor();
+        tab.inner
+            .write()
+            .finish_stream(reservation, None::<fn(&[u8]) -> std::io::Result<()>>)
+            .unwrap();
+        let changes = tab
+            .changes_since(cursor)
+            .map(|(k, _)| k)
+            .collect::<Vec<_>>();
+        assert_eq!(changes, vec![streamed, flushed]);
+    }
+}
@@ -0,0 +1,270 @@
+use std::io::{Read, Seek, SeekFrom};
+
+use arrayref::array_ref;
+
+use crate::Mapping;
+
+/// Chunks are never cut shorter than this, except at the end of a file.
+const MIN_CHUNK_SIZE: usize = 16 * 1024;
+/// Chunks are always cut at this length.
+const MAX_CHUNK_SIZE: usize = 256 * 1024;
+/// A cut point is where the low 16 bits of the rolling hash are zero, so chunks average around 64 KiB past the minimum.
+const CUT_MASK: u64 = (1 << 16) - 1;
+
+/// Magic bytes that start every file manifest.
+const MANIFEST_MAGIC: &[u8; 8] = b"meshfil1";
+
+/// Random values for the gear rolling hash, one per byte value, generated with splitmix64.
+static GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut state = 0x6d65_7368_616e_696eu64;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// Finds where the first chunk of the given data ends, using a gear-based rolling hash. Since the hash only depends on the last 64 bytes seen, an edit only moves the cut points near it, and the rest of the chunks stay the same.
+fn cut_point(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK_SIZE {
+        return data.len();
+    }
+    let end = data.len().min(MAX_CHUNK_SIZE);
+    let mut hash = 0u64;
+    for (i, &b) in data.iter().enumerate().take(end).skip(MIN_CHUNK_SIZE) {
+        hash = (hash << 1).wrapping_add(GEAR[b as usize]);
+        if hash & CUT_MASK == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+impl Mapping {
+    /// Stores a file, read from the given reader, as content-defined chunks, each under its own hash, plus a manifest listing the chunks. Returns the key of the manifest, which identifies the whole file.
+    ///
+    /// Because chunk boundaries depend only on the content around them, different versions of a large file share most of their chunks.
+    pub fn put_file(&self, mut input: impl Read) -> std::io::Result<[u8; 32]> {
+        let mut manifest = MANIFEST_MAGIC.to_vec();
+        manifest.extend_from_slice(&[0u8; 8]);
+        let mut total_len = 0u64;
+        let mut buf = Vec::with_capacity(MAX_CHUNK_SIZE);
+        let mut eof = false;
+        loop {
+            while !eof && buf.len() < MAX_CHUNK_SIZE {
+                let filled = buf.len();
+                buf.resize(MAX_CHUNK_SIZE, 0);
+                let res = input.read(&mut buf[filled..]);
+                buf.truncate(filled + *res.as_ref().unwrap_or(&0));
+                match res {
+                    Ok(0) => eof = true,
+                    Ok(_) => {}
+                    Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                    Err(err) => return Err(err),
+                }
+            }
+            if buf.is_empty() {
+                break;
+            }
+            let cut = cut_point(&buf);
+            let key = self.put(&buf[..cut]);
+            manifest.extend_from_slice(&(cut as u64).to_le_bytes());
+            manifest.extend_from_slice(&key);
+            total_len += cut as u64;
+            buf.drain(..cut);
+        }
+        manifest[8..16].copy_from_slice(&total_len.to_le_bytes());
+        Ok(self.put(&manifest))
+    }
+
+    /// Opens a file stored with [Mapping::put_file], given the key of its manifest. Chunks are read lazily, and only the parts that are needed.
+    pub fn open_file(&self, key: [u8; 32]) -> std::io::Result<FileReader<'_>> {
+        let manifest = self.get(key).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, "file manifest not found")
+        })?;
+        if manifest.len() < 16
+            || &manifest[..8] != MANIFEST_MAGIC
+            || (manifest.len() - 16) % 40 != 0
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "not a file manifest",
+            ));
+        }
+        let len = u64::from_le_bytes(*array_ref![manifest, 8, 8]);
+        let mut starts = vec![];
+        let mut keys = vec![];
+        let mut posn = 0u64;
+        for entry in manifest[16..].chunks_exact(40) {
+            starts.push(posn);
+            keys.push(*array_ref![entry, 8, 32]);
+            posn = posn
+                .checked_add(u64::from_le_bytes(*array_ref![entry, 0, 8]))
+                .ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "file manifest lengths overflow",
+                    )
+                })?;
+        }
+        if posn != len {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "file manifest lengths are inconsistent",
+            ));
+        }
+        Ok(FileReader {
+            mapping: self,
+            starts,
+            keys,
+            len,
+            posn: 0,
+        })
+    }
+}
+
+/// A seekable reader over a chunked file, returned by [Mapping::open_file].
+pub struct FileReader<'a> {
+    mapping: &'a Mapping,
+    starts: Vec<u64>,
+    keys: Vec<[u8; 32]>,
+    len: u64,
+    posn: u64,
+}
+
+impl FileReader<'_> {
+    /// The total length of the file.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Whether the file is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Read for FileReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.posn >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let idx = self.starts.partition_point(|&s| s <= self.posn) - 1;
+        let end = self.starts.get(idx + 1).copied().unwrap_or(self.len);
+        let chunk_len = end - self.starts[idx];
+        // a chunk of the wrong length would shift the rest of the file, or look like its end
+        let mismatch = || {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "chunk does not match its manifest entry",
+            )
+        };
+        let missing = || std::io::Error::new(std::io::ErrorKind::NotFound, "chunk missing");
+        if self.mapping.value_len(self.keys[idx]).ok_or_else(missing)? != chunk_len {
+            return Err(mismatch());
+        }
+        let offset = self.posn - self.starts[idx];
+        let wanted = (chunk_len - offset).min(buf.len() as u64) as usize;
+        let chunk = self
+            .mapping
+            .get_range(self.keys[idx], offset as usize..offset as usize + wanted)
+            .ok_or_else(missing)?;
+        if chunk.len() != wanted {
+            return Err(mismatch());
+        }
+        buf[..chunk.len()].copy_from_slice(&chunk);
+        self.posn += chunk.len() as u64;
+        Ok(chunk.len())
+    }
+}
+
+impl Seek for FileReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let posn = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(d) => self.len.checked_add_signed(d),
+            SeekFrom::Current(d) => self.posn.checked_add_signed(d),
+        }
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "seek to a negative position",
+            )
+        })?;
+        self.posn = posn;
+        Ok(posn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunked_file_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Mapping::open(dir.path().join("files.db")).unwrap();
+        let rng = fastrand::Rng::with_seed(42);
+        let data: Vec<u8> = (0..3_000_000).map(|_| rng.u8(..)).collect();
+        let root = db.put_file(&data[..]).unwrap();
+
+        let mut reader = db.open_file(root).unwrap();
+        assert_eq!(reader.len(), data.len() as u64);
+        let mut readback = vec![];
+        reader.read_to_end(&mut readback).unwrap();
+        assert_eq!(readback, data);
+
+        reader.seek(SeekFrom::Start(1_234_567)).unwrap();
+        let mut buf = [0u8; 100_000];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &data[1_234_567..][..100_000]);
+
+        // an edit in the middle leaves most chunks shared
+        let chunks = |root| {
+            let manifest = db.get(root).unwrap();
+            manifest[16..]
+                .chunks_exact(40)
+                .map(|e| *array_ref![e, 8, 32])
+                .collect::<Vec<[u8; 32]>>()
+        };
+        let mut edited = data.clone();
+        edited.splice(1_500_000..1_500_010, *b"inserted some bytes");
+        let edited_root = db.put_file(&edited[..]).unwrap();
+        let old_chunks = chunks(root);
+        let new_chunks = chunks(edited_root);
+        let shared = new_chunks.iter().filter(|c| old_chunks.contains(c)).count();
+        assert!(shared + 3 >= new_chunks.len());
+
+        let empty = db.put_file(&[][..]).unwrap();
+        assert!(db.open_file(empty).unwrap().is_empty());
+
+        // a manifest whose lengths do not match its chunks is an error, not an early end of file or a shifted one
+        let short = db.put(b"abc");
+        let long = db.put(b"abcdefghijklmnopqrstuvwxyz");
+        for entries in [[(10u64, short), (10, short)], [(10, long), (10, short)]] {
+            let mut manifest = MANIFEST_MAGIC.to_vec();
+            manifest.extend_from_slice(&20u64.to_le_bytes());
+            for (len, key) in entries {
+                manifest.extend_from_slice(&len.to_le_bytes());
+                manifest.extend_from_slice(&key);
+            }
+            let mut reader = db.open_file(db.put(&manifest)).unwrap();
+            let err = reader.read_to_end(&mut vec![]).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        }
+        let mut overflowing = MANIFEST_MAGIC.to_vec();
+        overflowing.extend_from_slice(&0u64.to_le_bytes());
+        for _ in 0..2 {
+            overflowing.extend_from_slice(&(1u64 << 63).to_le_bytes());
+            overflowing.extend_from_slice(&short);
+        }
+        let err = db.open_file(db.put(&overflowing)).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,171 @@
+use std::{collections::HashSet, path::Path};
+
+use parking_lot::RwLockWriteGuard;
+
+use crate::{Builder, Mapping};
+
+/// What a compaction copied into the new database, and what it left behind.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CompactionReport {
+    /// How many key-value pairs were kept.
+    pub kept: u64,
+    /// The total length of the values that were kept.
+    pub kept_bytes: u64,
+    /// The keys that were removed.
+    pub removed: Vec<[u8; 32]>,
+    /// The total length of the values that were removed.
+    pub removed_bytes: u64,
+}
+
+impl Mapping {
+    /// Garbage-collects the database into a new file, which must not already exist. Only the values reachable from the given roots, following the keys that `links` extracts from each value, are copied over, along with a fresh, compact HAMT. Everything else is dropped.
+    pub fn compact_reachable(
+        &self,
+        dest: impl AsRef<Path>,
+        roots: impl IntoIterator<Item = [u8; 32]>,
+        links: impl FnMut(&[u8]) -> Vec<[u8; 32]>,
+    ) -> std::io::Result<CompactionReport> {
+        let mut builder = Builder::create_with(dest, self.inner.read().encoding())?;
+        let mut report = CompactionReport::default();
+        let mut kept = HashSet::new();
+        let mut res = Ok(());
+        self.walk_dag(roots, links, |key, value| {
+            if let (Ok(()), Some(value)) = (&res, value) {
+                res = builder.insert(key, &value);
+                kept.insert(key);
+                report.kept += 1;
+                report.kept_bytes += value.len() as u64;
+            }
+        });
+        res?;
+        builder.finish()?;
+        let inner = self.inner.read();
+        inner
+            .walk_leaves(inner.root(), &mut |key, value, _| {
+                if !kept.contains(&key) {
+                    report.removed.push(key);
+                    report.removed_bytes += value.len() as u64;
+                }
+            })
+            .expect("db corruption: dangling ptr");
+        Ok(report)
+    }
+
+    /// Rewrites the database into a new file, which must not already exist, keeping only the key-value pairs for which `keep` returns true, along with a fresh, compact HAMT. `keep` is also given the offset of each data record in the log. Since the log is append-only, records flushed later always have larger offsets.
+    ///
+    /// The database is flushed first, so that every data record has an offset, and writers are blocked until the compaction finishes.
+    pub fn compact_with(
+        &self,
+        dest: impl AsRef<Path>,
+        mut keep: impl FnMut([u8; 32], &[u8], u64) -> bool,
+    ) -> std::io::Result<CompactionReport> {
+        let mut builder = Builder::create_with(dest, self.inner.read().encoding())?;
+        let mut inner = self.inner.write();
+        inner.flush(true);
+        let inner = RwLockWriteGuard::downgrade(inner);
+        let mut report = CompactionReport::default();
+        let mut res = Ok(());
+        inner
+            .walk_leaves(inner.root(), &mut |key, value, offset| {
+                if res.is_err() {
+                    return;
+                }
+                if keep(key, value, offset.expect("flushed leaf not on disk")) {
+                    res = builder.insert(key, value);
+                    report.kept += 1;
+                    report.kept_bytes += value.len() as u64;
+                } else {
+                    report.removed.push(key);
+                    report.removed_bytes += value.len() as u64;
+                }
+            })
+            .expect("db corruption: dangling ptr");
+        res?;
+        builder.finish()?;
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use arrayref::array_ref;
+
+    use super::*;
+
+    #[test]
+    fn compact_reachable_simple() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Mapping::open(dir.path().join("gc.db")).unwrap();
+        // each value links to the value with the key in its first 32 bytes, if any
+        let links = |v: &[u8]| {
+            if v.len() >= 32 {
+                vec![*array_ref![v, 0, 32]]
+            } else {
+                vec![]
+            }
+        };
+        let tail = db.put(b"tail");
+        let mut head = tail;
+        for i in 0u8..10 {
+            let mut value = head.to_vec();
+            value.push(i);
+            head = db.put(&value);
+        }
+        let garbage: Vec<_> = (0u64..100).map(|i| db.put(&i.to_le_bytes())).collect();
+
+        let report = db
+            .compact_reachable(dir.path().join("compacted.db"), [head], links)
+            .unwrap();
+        assert_eq!(report.kept, 11);
+        assert_eq!(report.kept_bytes, 4 + 10 * 33);
+        assert_eq!(report.removed.len(), 100);
+        assert_eq!(report.removed_bytes, 800);
+        drop(db);
+        let compacted = Mapping::open(dir.path().join("compacted.db")).unwrap();
+        assert!(compacted.missing_blocks(head, links).is_empty());
+        assert_eq!(&compacted.get(tail).unwrap()[..], b"tail");
+        assert!(garbage.iter().all(|k| compacted.get(*k).is_none()));
+    }
+
+    #[test]
+    fn compact_with_predicate() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Mapping::open(dir.path().join("policy.db")).unwrap();
+        let small: Vec<_> = (0u64..50).map(|i| db.put(&i.to_le_bytes())).collect();
+        let big = db.put(&[0xaa; 10_000]);
+        let denied = small[7];
+        db.flush();
+        let late = db.put(b"written after the cutoff");
+        let mut cutoff = 0;
+        db.compact_with(dir.path().join("probe.db"), |k, _, offset| {
+            if k != late {
+                cutoff = cutoff.max(offset);
+            }
+            true
+        })
+        .unwrap();
+
+        let report = db
+            .compact_with(dir.path().join("filtered.db"), |k, v, offset| {
+                v.len() < 1000 && k != denied && offset <= cutoff
+            })
+            .unwrap();
+        assert_eq!(report.kept, 49);
+        assert_eq!(report.kept_bytes, 49 * 8);
+        let mut removed = report.removed.clone();
+        removed.sort_unstable();
+        let mut expected = vec![big, denied, late];
+        expected.sort_unstable();
+        assert_eq!(removed, expected);
+        assert_eq!(report.removed_bytes, 10_000 + 8 + 24);
+
+        drop(db);
+        let filtered = Mapping::open(dir.path().join("filtered.db")).unwrap();
+        for k in small.iter().filter(|k| **k != denied) {
+            assert!(filtered.get(*k).is_some());
+        }
+        for k in expected {
+            assert!(filtered.get(k).is_none());
+        }
+    }
+}
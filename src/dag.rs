@@ -0,0 +1,138 @@
+use std::collections::HashSet;
+
+use bytes::Bytes;
+
+use crate::Mapping;
+
+impl Mapping {
+    /// Walks the Merkle DAG below the given roots, depth-first, visiting every distinct key once. `links` extracts the keys a value points to; `visit` is called with each key and its value, or `None` if the key is missing from the database. Values are handed out as [Bytes], so flushed values are never copied.
+    pub fn walk_dag(
+        &self,
+        roots: impl IntoIterator<Item = [u8; 32]>,
+        mut links: impl FnMut(&[u8]) -> Vec<[u8; 32]>,
+        mut visit: impl FnMut([u8; 32], Option<Bytes>),
+    ) {
+        let mut seen = HashSet::new();
+        let mut stack: Vec<[u8; 32]> = roots.into_iter().collect();
+        stack.reverse();
+        while let Some(key) = stack.pop() {
+            if !seen.insert(key) {
+                continue;
+            }
+            match self.get_bytes(key) {
+                Some(value) => {
+                    let mut children = links(&value);
+                    visit(key, Some(value));
+                    children.reverse();
+                    stack.extend(children.into_iter().filter(|c| !seen.contains(c)));
+                }
+                None => visit(key, None),
+            }
+        }
+    }
+
+    /// Fetches every value in the DAG below a root, in depth-first order, with each distinct key appearing once. Fails with [std::io::ErrorKind::NotFound] if any of them is missing.
+    pub fn fetch_dag(
+        &self,
+        root: [u8; 32],
+        links: impl FnMut(&[u8]) -> Vec<[u8; 32]>,
+    ) -> std::io::Result<Vec<([u8; 32], Bytes)>> {
+        let mut fetched = vec![];
+        let mut missing = None;
+        self.walk_dag([root], links, |key, value| match value {
+            Some(value) => fetched.push((key, value)),
+            None => missing = missing.or(Some(key)),
+        });
+        if let Some(missing) = missing {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("DAG block {} missing", hex::encode(missing)),
+            ));
+        }
+        Ok(fetched)
+    }
+
+    /// Lists every key in the DAG below a root that is referenced but missing from the database. The DAG is fully present exactly when this is empty.
+    pub fn missing_blocks(
+        &self,
+        root: [u8; 32],
+        links: impl FnMut(&[u8]) -> Vec<[u8; 32]>,
+    ) -> Vec<[u8; 32]> {
+        let mut missing = vec![];
+        self.walk_dag([root], links, |key, value| {
+            if value.is_none() {
+                missing.push(key)
+            }
+        });
+        missing
+    }
+
+    /// Finds every key that is present in the database and reachable from a set of pinned roots.
+    pub fn reachable(
+        &self,
+        pins: impl IntoIterator<Item = [u8; 32]>,
+        links: impl FnMut(&[u8]) -> Vec<[u8; 32]>,
+    ) -> HashSet<[u8; 32]> {
+        let mut reachable = HashSet::new();
+        self.walk_dag(pins, links, |key, value| {
+            if value.is_some() {
+                reachable.insert(key);
+            }
+        });
+        reachable
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use arrayref::array_ref;
+
+    use super::*;
+
+    /// Values are a one-byte tag followed by any number of 32-byte links.
+    fn links(value: &[u8]) -> Vec<[u8; 32]> {
+        value[1..]
+            .chunks_exact(32)
+            .map(|c| *array_ref![c, 0, 32])
+            .collect()
+    }
+
+    fn node(tag: u8, children: &[[u8; 32]]) -> Vec<u8> {
+        let mut v = vec![tag];
+        for c in children {
+            v.extend_from_slice(c);
+        }
+        v
+    }
+
+    #[test]
+    fn dag_simple() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Mapping::open(dir.path().join("dag.db")).unwrap();
+        let leaf_a = db.put(&node(1, &[]));
+        let leaf_b = db.put(&node(2, &[]));
+        let absent = *blake3::hash(&node(3, &[])).as_bytes();
+        let mid = db.put(&node(4, &[leaf_a, leaf_b]));
+        // a diamond: `leaf_a` is reachable twice
+        let root = db.put(&node(5, &[mid, leaf_a]));
+        let broken = db.put(&node(6, &[mid, absent]));
+        let unpinned = db.put(&node(7, &[]));
+
+        let fetched = db.fetch_dag(root, links).unwrap();
+        let keys: Vec<_> = fetched.iter().map(|(k, _)| *k).collect();
+        assert_eq!(keys, vec![root, mid, leaf_a, leaf_b]);
+        assert_eq!(&fetched[1].1[..], &node(4, &[leaf_a, leaf_b])[..]);
+        assert!(db.missing_blocks(root, links).is_empty());
+
+        assert_eq!(
+            db.fetch_dag(broken, links).unwrap_err().kind(),
+            std::io::ErrorKind::NotFound
+        );
+        assert_eq!(db.missing_blocks(broken, links), vec![absent]);
+
+        let reachable = db.reachable([root, broken], links);
+        assert_eq!(reachable.len(), 5);
+        assert!(!reachable.contains(&unpinned));
+        assert!(!reachable.contains(&absent));
+    }
+}
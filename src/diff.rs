@@ -0,0 +1,108 @@
+use std::sync::Arc;
+
+use crate::Mapping;
+
+/// The keys that differ between two versions of a database.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Diff {
+    /// Keys that are only in the newer version.
+    pub added: Vec<[u8; 32]>,
+    /// Keys that are only in the older version.
+    pub removed: Vec<[u8; 32]>,
+}
+
+impl Mapping {
+    /// Compares this database with another one, typically a replica or a backup, returning the keys that this one has and the other lacks as added, and the reverse as removed. Subtrees with the same Merkle hash on both sides are skipped, so this is cheap when the two are mostly the same.
+    pub fn diff(&self, base: &Mapping) -> Diff {
+        let mut diff = Diff::default();
+        if Arc::ptr_eq(&self.inner, &base.inner) {
+            return diff;
+        }
+        // always lock in the same order, so that two opposite diffs cannot deadlock
+        let (ours, theirs) = if Arc::as_ptr(&self.inner) < Arc::as_ptr(&base.inner) {
+            let ours = self.inner.read();
+            (ours, base.inner.read())
+        } else {
+            let theirs = base.inner.read();
+            (self.inner.read(), theirs)
+        };
+        ours.diff(
+            ours.root(),
+            &theirs,
+            theirs.root(),
+            &mut |k| diff.added.push(k),
+            &mut |k| diff.removed.push(k),
+        )
+        .expect("db corruption: dangling ptr");
+        diff
+    }
+
+    /// Returns what changed since an earlier root of this database, as given by [Mapping::flushed_root]. Subtrees that were not rewritten since are skipped, so the cost is proportional to what changed. The earlier root must still be intact, so it cannot be older than the last purge, punch, dropped segment or compaction; otherwise, this fails with [std::io::ErrorKind::InvalidInput].
+    pub fn diff_since(&self, base_root: u64) -> std::io::Result<Diff> {
+        let mut diff = Diff::default();
+        let inner = self.inner.read();
+        let base = inner.load_root(base_root)?;
+        inner
+            .diff(
+                inner.root(),
+                &inner,
+                &base,
+                &mut |k| diff.added.push(k),
+                &mut |k| diff.removed.push(k),
+            )
+            .map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("the given root is no longer intact: {e:#}"),
+                )
+            })?;
+        Ok(diff)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_roots() {
+        let key = |i: u32| *blake3::hash(&i.to_le_bytes()).as_bytes();
+        let sorted = |mut keys: Vec<[u8; 32]>| {
+            keys.sort_unstable();
+            keys
+        };
+        let a = Mapping::in_memory();
+        let b = Mapping::in_memory();
+        for i in 0..3000u32 {
+            a.put(&i.to_le_bytes());
+            if i % 100 != 0 {
+                b.put(&i.to_le_bytes());
+            }
+        }
+        b.put(b"only in b");
+        a.flush();
+        let yesterday = a.flushed_root().unwrap();
+
+        let diff = a.diff(&b);
+        assert_eq!(
+            sorted(diff.added.clone()),
+            sorted((0..3000).step_by(100).map(key).collect())
+        );
+        assert_eq!(diff.removed, vec![*blake3::hash(b"only in b").as_bytes()]);
+        assert_eq!(b.diff(&a).added, diff.removed);
+        assert_eq!(a.diff(&a), Diff::default());
+
+        // changes since a flush, both flushed and not
+        let new = a.put(b"new");
+        a.remove(key(7));
+        a.flush();
+        a.remove(key(8));
+        let diff = a.diff_since(yesterday).unwrap();
+        assert_eq!(diff.added, vec![new]);
+        assert_eq!(sorted(diff.removed), sorted(vec![key(7), key(8)]));
+        assert_eq!(
+            a.diff_since(4096).err().unwrap().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+    }
+}
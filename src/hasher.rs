@@ -0,0 +1,15 @@
+/// A hash function `H` that maps every value to its content-addressed key, so that `H(v) = k`.
+pub trait ContentHasher: Send + Sync + 'static {
+    /// Computes the key of a value.
+    fn hash(&self, value: &[u8]) -> [u8; 32];
+}
+
+/// The default [ContentHasher], based on blake3.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Blake3Hasher;
+
+impl ContentHasher for Blake3Hasher {
+    fn hash(&self, value: &[u8]) -> [u8; 32] {
+        *blake3::hash(value).as_bytes()
+    }
+}
@@ -1,21 +1,153 @@
-use std::{borrow::Cow, path::Path, sync::Arc, time::Duration};
+use std::{
+    borrow::Cow,
+    io::Read,
+    ops::{Bound, RangeBounds},
+    path::Path,
+    sync::{Arc, mpsc},
+    time::Duration,
+};
 
 use bytes::Bytes;
 use parking_lot::RwLock;
+use record::Encoding;
+use segment::SegmentDir;
 use table::Table;
+use zeroize::Zeroizing;
 
+mod builder;
+mod changes;
+mod chunking;
+mod compaction;
+mod dag;
+mod diff;
+mod hasher;
+mod merkle;
 mod record;
+mod replication;
+mod segment;
+mod storage;
 mod table;
+mod transaction;
+
+pub use builder::Builder;
+pub use changes::Changes;
+pub use chunking::FileReader;
+pub use compaction::CompactionReport;
+pub use diff::Diff;
+pub use hasher::{Blake3Hasher, ContentHasher};
+pub use merkle::{Proof, ProofNode, verify_proof};
+pub use replication::ReplicationCursor;
+pub use storage::{FileStorage, MemoryStorage, MmapStorage, Storage};
+pub use transaction::Transaction;
 
 /// An on-disk, append-only Meshanina database.
 pub struct Mapping {
     inner: Arc<RwLock<Table>>,
+    hasher: Arc<dyn ContentHasher>,
+    strict: bool,
 }
 
-impl Mapping {
-    /// Opens a mapping, given a filename.
-    pub fn open(fname: impl AsRef<Path>) -> std::io::Result<Self> {
-        let table = Table::open(fname.as_ref())?;
+/// Options for opening a [Mapping].
+#[derive(Clone)]
+pub struct Options {
+    hasher: Arc<dyn ContentHasher>,
+    strict: bool,
+    segment_size: u64,
+    mmap: bool,
+    encoding: Encoding,
+    adopt_unauthenticated: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            hasher: Arc::new(Blake3Hasher),
+            strict: false,
+            segment_size: 1 << 30,
+            mmap: true,
+            encoding: Encoding::default(),
+            adopt_unauthenticated: false,
+        }
+    }
+}
+
+impl Options {
+    /// Sets the [ContentHasher] that maps values to keys. Defaults to [Blake3Hasher].
+    pub fn hasher(mut self, hasher: impl ContentHasher) -> Self {
+        self.hasher = Arc::new(hasher);
+        self
+    }
+
+    /// Sets whether [Mapping::insert] rejects key-value pairs where the key is not the hash of the value. Defaults to false.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Sets the length past which a segmented database, opened with [Options::open_dir], starts a new segment file. Defaults to 1 GiB.
+    pub fn segment_size(mut self, segment_size: u64) -> Self {
+        self.segment_size = segment_size;
+        self
+    }
+
+    /// Sets whether files are read through an mmap ([MmapStorage]), rather than with plain positional reads ([FileStorage]). Defaults to true.
+    pub fn mmap(mut self, mmap: bool) -> Self {
+        self.mmap = mmap;
+        self
+    }
+
+    /// Compresses values at least `threshold` bytes long with LZ4 when they are written, if that makes them shorter. Values of 4 GiB or more are never compressed. Compressed values are decompressed transparently when read, but cannot be borrowed zero-copy. Off by default.
+    pub fn compression(mut self, threshold: usize) -> Self {
+        self.encoding.compress_threshold = Some(threshold);
+        self
+    }
+
+    /// Encrypts values at rest with the given 256-bit key. Each value is sealed with XChaCha20-Poly1305, under a key derived from this one with the database's divider as salt, and bound to its own key; HAMT nodes are left in the clear, so the structure of the database, but not its contents, stays visible.
+    ///
+    /// Values that were written before encryption was turned on stay readable. Once a database has been opened with a key, opening it with a different key or none fails with [std::io::ErrorKind::PermissionDenied].
+    pub fn encryption_key(mut self, key: impl Into<Zeroizing<[u8; 32]>>) -> Self {
+        self.encoding.key = Some(key.into());
+        self
+    }
+
+    /// Authenticates roots with the given 256-bit secret. Every root written carries a keyed blake3 MAC over its contents and the MAC of the root before it, so the roots form a chain that cannot be forged or rewritten without the secret. Opening with the secret walks the chain back, failing with [std::io::ErrorKind::InvalidData] if it has been tampered with, or if the latest root is not authenticated at all.
+    ///
+    /// Each MAC also covers the Merkle hash of the tree below its root (see [Mapping::root_hash]), which is recomputed from disk on open, so the HAMT nodes and keys below the latest root cannot be rewritten either. That costs a read of every HAMT node. Values are only covered through their keys, so they should be read with [Mapping::get_verified]. Rolling the file back to an older root cannot be told apart from a crash either. Once a database has been opened with a secret, opening it with a different secret or none fails with [std::io::ErrorKind::PermissionDenied].
+    pub fn authentication_key(mut self, secret: impl Into<Zeroizing<[u8; 32]>>) -> Self {
+        self.encoding.auth_key = Some(secret.into());
+        self
+    }
+
+    /// Lets [Options::authentication_key] turn authentication on for an existing database, whose latest root is not authenticated yet. That root is trusted as is, and chained to by every root written after it. Databases that were authenticated before are never adopted again. Off by default.
+    pub fn adopt_unauthenticated(mut self, adopt: bool) -> Self {
+        self.adopt_unauthenticated = adopt;
+        self
+    }
+
+    /// Opens a mapping with these options, given a filename.
+    pub fn open(self, fname: impl AsRef<Path>) -> std::io::Result<Mapping> {
+        let table = Table::new(segment::open_file(fname.as_ref(), self.mmap)?)?;
+        self.start(table)
+    }
+
+    /// Opens a segmented mapping with these options, given a directory that holds its segment files. The directory is created if it does not exist.
+    pub fn open_dir(self, dir: impl AsRef<Path>) -> std::io::Result<Mapping> {
+        let table = Table::open_dir(SegmentDir {
+            path: dir.as_ref().to_owned(),
+            segment_size: self.segment_size,
+            mmap: self.mmap,
+        })?;
+        self.start(table)
+    }
+
+    /// Opens a mapping with these options on an arbitrary [Storage].
+    pub fn open_storage(self, storage: impl Storage) -> std::io::Result<Mapping> {
+        let table = Table::new(Box::new(storage))?;
+        self.start(table)
+    }
+
+    fn start(self, mut table: Table) -> std::io::Result<Mapping> {
+        table.set_encoding(self.encoding, self.adopt_unauthenticated)?;
         let inner = Arc::new(RwLock::new(table));
         let inner_weak = Arc::downgrade(&inner);
         // TODO a better, "batch-timer" approach
@@ -23,16 +155,64 @@ impl Mapping {
             .name("mesh-flush".into())
             .spawn(move || {
                 loop {
+                    std::thread::sleep(Duration::from_secs(30));
+                    // the strong reference must only be held while flushing, or the table (and its file lock) outlives the mapping
                     if let Some(inner) = inner_weak.upgrade() {
                         inner.write().flush(true);
-                        std::thread::sleep(Duration::from_secs(30))
                     } else {
                         return;
                     }
                 }
             })
             .unwrap();
-        Ok(Mapping { inner })
+        Ok(Mapping {
+            inner,
+            hasher: self.hasher,
+            strict: self.strict,
+        })
+    }
+}
+
+/// A wait for a key to be inserted, returned by [Mapping::watch]. Dropping it gives up on the wait.
+pub struct Watch {
+    rx: mpsc::Receiver<()>,
+    /// Tells the mapping whether anyone still waits
+    _alive: Arc<()>,
+}
+
+impl Watch {
+    /// Blocks until the key is inserted. Fails if the mapping is dropped first.
+    pub fn recv(&self) -> Result<(), mpsc::RecvError> {
+        self.rx.recv()
+    }
+
+    /// Returns whether the key has been inserted, without blocking.
+    pub fn try_recv(&self) -> Result<(), mpsc::TryRecvError> {
+        self.rx.try_recv()
+    }
+
+    /// Blocks until the key is inserted, or the timeout runs out.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<(), mpsc::RecvTimeoutError> {
+        self.rx.recv_timeout(timeout)
+    }
+}
+
+impl Mapping {
+    /// Opens a mapping with default options, given a filename.
+    pub fn open(fname: impl AsRef<Path>) -> std::io::Result<Self> {
+        Options::default().open(fname)
+    }
+
+    /// Creates an empty mapping with default options that lives only in memory, and is lost when dropped.
+    pub fn in_memory() -> Self {
+        Options::default()
+            .open_storage(MemoryStorage::new())
+            .expect("in-memory storage cannot fail")
+    }
+
+    /// Opens a segmented mapping with default options, given a directory. See [Options::open_dir].
+    pub fn open_dir(dir: impl AsRef<Path>) -> std::io::Result<Self> {
+        Options::default().open_dir(dir)
     }
 
     /// Flushes the mapping to disk.
@@ -45,12 +225,211 @@ impl Mapping {
     pub fn get(&self, key: [u8; 32]) -> Option<Cow<'_, [u8]>> {
         let inner = self.inner.read();
         let bts = inner.lookup(key)?;
-        Some(unsafe { std::mem::transmute(bts) })
+        Some(unsafe { std::mem::transmute::<Cow<'_, [u8]>, Cow<'_, [u8]>>(bts) })
+    }
+
+    /// Gets a value as refcounted [Bytes]. Values that have been flushed are not copied: the handle points straight into the mmapped file, and can be sent across threads and outlive the mapping.
+    pub fn get_bytes(&self, key: [u8; 32]) -> Option<Bytes> {
+        let inner = self.inner.read();
+        inner.lookup_with(inner.root(), key, |v| inner.to_bytes(v))
+    }
+
+    /// Gets a byte range of a value, clamped to the length of the value. Only the requested part of the value is read, so the range cannot be verified against the checksum or hash of the whole value.
+    pub fn get_range(
+        &self,
+        key: [u8; 32],
+        range: impl RangeBounds<usize>,
+    ) -> Option<Cow<'_, [u8]>> {
+        let inner = self.inner.read();
+        let bts = inner.lookup_at(key, |v| {
+            let start = match range.start_bound() {
+                Bound::Included(&i) => i,
+                Bound::Excluded(&i) => i.saturating_add(1),
+                Bound::Unbounded => 0,
+            };
+            let end = match range.end_bound() {
+                Bound::Included(&i) => i.saturating_add(1),
+                Bound::Excluded(&i) => i,
+                Bound::Unbounded => usize::MAX,
+            };
+            v.read(start as u64, end as u64)
+                .expect("db corruption: value out of bounds")
+        })?;
+        Some(unsafe { std::mem::transmute::<Cow<'_, [u8]>, Cow<'_, [u8]>>(bts) })
+    }
+
+    /// Gets the length of a value, without reading the value itself.
+    pub fn value_len(&self, key: [u8; 32]) -> Option<u64> {
+        let inner = self.inner.read();
+        inner.lookup_at(key, |v| v.len())
+    }
+
+    /// Gets a key-value pair, rehashing the value with the configured [ContentHasher]. Fails with [std::io::ErrorKind::InvalidData] if the value read back does not hash to its key.
+    pub fn get_verified(&self, key: [u8; 32]) -> std::io::Result<Option<Cow<'_, [u8]>>> {
+        let Some(value) = self.get(key) else {
+            return Ok(None);
+        };
+        if self.hasher.hash(&value) != key {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "value does not hash to its key",
+            ));
+        }
+        Ok(Some(value))
+    }
+
+    /// Inserts a key-value pair. In strict mode, fails if the key is not the hash of the value. Also fails if values are encrypted and this one is 4 GiB or longer.
+    pub fn insert(&self, key: [u8; 32], value: &[u8]) -> std::io::Result<()> {
+        self.check_key(key, value)?;
+        let mut inner = self.inner.write();
+        inner.check_value_len(value.len() as u64)?;
+        inner.insert(key, value);
+        Ok(())
+    }
+
+    /// Inserts a value under its own hash, returning the key.
+    ///
+    /// Panics if values are encrypted and this one is 4 GiB or longer; use [Mapping::insert] to handle that case.
+    pub fn put(&self, value: &[u8]) -> [u8; 32] {
+        let key = self.hasher.hash(value);
+        let mut inner = self.inner.write();
+        inner
+            .check_value_len(value.len() as u64)
+            .expect("value too long to be encrypted");
+        inner.insert(key, value);
+        key
+    }
+
+    /// Inserts a key whose value, exactly `length` bytes long, is streamed from a reader. The value may be larger than 4 GiB, and is never buffered whole in memory, unless values are encrypted: they are encrypted whole, so the value is read into memory first, and must be shorter than 4 GiB. In strict mode, fails if the key is not the hash of the value.
+    pub fn insert_stream(
+        &self,
+        key: [u8; 32],
+        length: u64,
+        value: impl std::io::Read,
+    ) -> std::io::Result<()> {
+        let mut value = value.take(length);
+        if self.inner.read().encrypts() {
+            // values are encrypted as a whole, so they must be buffered
+            self.inner.read().check_value_len(length)?;
+            let mut buffer = vec![];
+            value.read_to_end(&mut buffer)?;
+            if buffer.len() as u64 != length {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "value shorter than the given length",
+                ));
+            }
+            return self.insert(key, &buffer);
+        }
+        let Some(mut reservation) = self.inner.write().reserve_stream(key, length)? else {
+            return Ok(());
+        };
+        // the reader may be slow, so the lock is only taken to write each piece
+        let mut buf = vec![0u8; 65536];
+        let res = loop {
+            match value.read(&mut buf) {
+                Ok(0) => break Ok(()),
+                Ok(n) => {
+                    if let Err(err) = self.inner.write().write_stream(&mut reservation, &buf[..n]) {
+                        break Err(err);
+                    }
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(err) => break Err(err),
+            }
+        };
+        let mut inner = self.inner.write();
+        match res {
+            Ok(()) => inner.finish_stream(
+                reservation,
+                self.strict.then_some(|v: &[u8]| self.check_key(key, v)),
+            ),
+            Err(err) => {
+                inner.abandon_stream(reservation)?;
+                Err(err)
+            }
+        }
+    }
+
+    /// In strict mode, checks that the key is the hash of the value.
+    fn check_key(&self, key: [u8; 32], value: &[u8]) -> std::io::Result<()> {
+        if self.strict && self.hasher.hash(value) != key {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "key is not the hash of the value",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Removes a key, so that it is unreachable from every later root. Returns whether the key existed. The value stays in the file until [Mapping::purge] is called.
+    ///
+    /// This is an escape hatch for takedowns and leaked secrets; meshanina is otherwise write-once.
+    pub fn remove(&self, key: [u8; 32]) -> bool {
+        self.inner.write().remove(key)
+    }
+
+    /// Flushes the mapping, then physically erases the data records of every removed key from the file. Returns how many records were erased.
+    ///
+    /// Values that an open [Transaction] still sees are kept, and only erased by a later purge. Values of removed keys that were previously borrowed from this mapping are erased too.
+    pub fn purge(&self) -> std::io::Result<u64> {
+        self.inner.write().purge()
+    }
+
+    /// Flushes the mapping, then releases every part of the file that is unreachable from the current root (old roots, superseded HAMT nodes and removed data records) by punching holes in it. The file becomes sparse, but no live record moves. Returns the total length of the unreachable ranges. Only supported on Linux.
+    ///
+    /// Records that an open [Transaction] can still see are kept. Values of removed keys that were previously borrowed from this mapping are not, and read back as zeroes.
+    pub fn punch_garbage(&self) -> std::io::Result<u64> {
+        self.inner.write().punch_garbage()
+    }
+
+    /// Lists the numbers of the segment files of a segmented mapping, oldest first. The last one is being appended to. A mapping opened from a single file has one segment, numbered 0.
+    pub fn segments(&self) -> Vec<u32> {
+        self.inner.read().segment_ids()
+    }
+
+    /// Drops a segment of a segmented mapping, deleting its file. The live records it holds are first copied to the end of the log and a new root is flushed, so nothing is lost. The segment being appended to cannot be dropped.
+    ///
+    /// Values previously borrowed from the segment stay valid, so its disk space is only released once the mapping is dropped. Fails with [std::io::ErrorKind::ResourceBusy] while an open [Transaction] can still see records in the segment.
+    pub fn drop_segment(&self, id: u32) -> std::io::Result<()> {
+        self.inner.write().drop_segment(id)
+    }
+
+    /// Returns the pointer to the last root flushed to disk, if any. It can be handed to [Mapping::diff_since] later, to find out what changed since this flush.
+    pub fn flushed_root(&self) -> Option<u64> {
+        self.inner.read().root_ptr()
+    }
+
+    /// Returns the Merkle hash of the HAMT, which commits to the set of keys in the database, unflushed ones included. It does not depend on insertion order, deletions or compaction, so two replicas holding the same keys always have the same root hash.
+    ///
+    /// Hashes of nodes on disk are remembered, so after the first call, this only rehashes what has changed.
+    pub fn root_hash(&self) -> [u8; 32] {
+        self.inner.read().root_hash()
     }
 
-    /// Inserts a key-value pair.
-    pub fn insert(&self, key: [u8; 32], value: &[u8]) {
-        self.inner.write().insert(key, value);
+    /// Builds a proof that a key is, or is not, in the database, which [verify_proof] checks against [Mapping::root_hash]. A client that trusts the root hash can then trust lookups served by an untrusted node.
+    pub fn prove(&self, key: [u8; 32]) -> Proof {
+        self.inner.read().prove(key)
+    }
+
+    /// Returns a [Watch] that is signalled once the key is inserted, or right away if it already exists. Waiting for a blob to show up is then just `watch(key).recv()`.
+    pub fn watch(&self, key: [u8; 32]) -> Watch {
+        let (tx, rx) = mpsc::channel();
+        let alive = Arc::new(());
+        self.inner.write().watch(key, tx, Arc::downgrade(&alive));
+        Watch { rx, _alive: alive }
+    }
+
+    /// Returns a channel that receives every key inserted from now on, in the order the inserts become visible. Keys that already existed are not sent again. The subscription ends when the receiver is dropped.
+    pub fn subscribe(&self) -> mpsc::Receiver<[u8; 32]> {
+        let (tx, rx) = mpsc::channel();
+        self.inner.write().subscribe(tx);
+        rx
+    }
+
+    /// Starts a transaction, whose inserts become visible together when it is committed.
+    pub fn transaction(&self) -> Transaction<'_> {
+        Transaction::new(self)
     }
 }
 
@@ -62,12 +441,723 @@ mod tests {
 
     #[test]
     fn db_simple() {
-        let tab = Mapping::open(Path::new("/tmp/test_meshanina.db")).unwrap();
+        let tab = Mapping::in_memory();
         for ctr in 0u64..100 {
             let k = *blake3::hash(format!("key{}", ctr).as_bytes()).as_bytes();
-            tab.insert(k, &ctr.to_le_bytes());
+            tab.insert(k, &ctr.to_le_bytes()).unwrap();
             let b = tab.get(k).unwrap();
             assert_eq!(array_ref![&b, 0, 8], &ctr.to_le_bytes());
         }
     }
+
+    #[test]
+    fn db_content_addressed() {
+        let dir = tempfile::tempdir().unwrap();
+        let tab = Options::default()
+            .strict(true)
+            .open(dir.path().join("strict.db"))
+            .unwrap();
+        let k = tab.put(b"hello");
+        assert_eq!(k, *blake3::hash(b"hello").as_bytes());
+        assert_eq!(&tab.get(k).unwrap()[..], b"hello");
+        tab.insert(*blake3::hash(b"world").as_bytes(), b"world")
+            .unwrap();
+        assert!(tab.insert([0u8; 32], b"world").is_err());
+        assert!(tab.get([0u8; 32]).is_none());
+        assert_eq!(&tab.get_verified(k).unwrap().unwrap()[..], b"hello");
+        assert!(tab.get_verified([0u8; 32]).unwrap().is_none());
+    }
+
+    #[test]
+    fn db_insert_stream() {
+        let dir = tempfile::tempdir().unwrap();
+        let fname = dir.path().join("stream.db");
+        let big: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+        let k = *blake3::hash(&big).as_bytes();
+        {
+            let tab = Options::default().strict(true).open(&fname).unwrap();
+            // short readers and bad keys are rolled back
+            assert!(
+                tab.insert_stream(k, big.len() as u64 + 1, &big[..])
+                    .is_err()
+            );
+            assert!(
+                tab.insert_stream([0u8; 32], big.len() as u64, &big[..])
+                    .is_err()
+            );
+            assert!(tab.get(k).is_none());
+            tab.insert_stream(k, big.len() as u64, &big[..]).unwrap();
+            assert_eq!(&tab.get(k).unwrap()[..], &big[..]);
+            let small = tab.put(b"small");
+            assert_eq!(&tab.get(small).unwrap()[..], b"small");
+            tab.flush();
+        }
+        let tab = Mapping::open(&fname).unwrap();
+        assert_eq!(&tab.get_verified(k).unwrap().unwrap()[..], &big[..]);
+    }
+
+    #[test]
+    fn db_torn_stream() {
+        let dir = tempfile::tempdir().unwrap();
+        let fname = dir.path().join("torn.db");
+        let k = {
+            let tab = Mapping::open(&fname).unwrap();
+            let k = tab.put(b"before");
+            tab.flush();
+            k
+        };
+        // a crash while streaming in a large value leaves a long tail without a root
+        let file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&fname)
+            .unwrap();
+        file.set_len(file.metadata().unwrap().len() + (30 << 20))
+            .unwrap();
+        let tab = Mapping::open(&fname).unwrap();
+        assert_eq!(&tab.get(k).unwrap()[..], b"before");
+    }
+
+    #[test]
+    fn db_remove_purge() {
+        let dir = tempfile::tempdir().unwrap();
+        let fname = dir.path().join("remove.db");
+        let secret = b"the password is hunter2";
+        let keys = {
+            let tab = Mapping::open(&fname).unwrap();
+            let keys: Vec<_> = (0u64..200).map(|i| tab.put(&i.to_le_bytes())).collect();
+            let leaked = tab.put(secret);
+            tab.flush();
+            let before = tab.flushed_root().unwrap();
+            assert!(tab.remove(leaked));
+            assert!(!tab.remove(leaked));
+            assert!(tab.get(leaked).is_none());
+            for k in keys.iter().step_by(3) {
+                assert!(tab.remove(*k));
+            }
+            // an open transaction keeps what it sees, until it is gone
+            let seen = tab.put(b"seen by a transaction");
+            tab.flush();
+            let tx = tab.transaction();
+            tab.remove(seen);
+            assert_eq!(tab.purge().unwrap(), 1 + 67);
+            assert_eq!(&tx.get(seen).unwrap()[..], b"seen by a transaction");
+            drop(tx);
+            assert_eq!(tab.purge().unwrap(), 1);
+            // roots from before a purge can no longer be diffed against
+            assert_eq!(
+                tab.diff_since(before).unwrap_err().kind(),
+                std::io::ErrorKind::InvalidInput
+            );
+            keys
+        };
+        let contents = std::fs::read(&fname).unwrap();
+        assert!(!contents.windows(secret.len()).any(|w| w == secret));
+        let tab = Mapping::open(&fname).unwrap();
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(tab.get(*k).is_some(), i % 3 != 0);
+        }
+        // removed keys can be inserted again
+        let again = tab.put(secret);
+        assert_eq!(&tab.get(again).unwrap()[..], secret);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn db_punch_garbage() {
+        let dir = tempfile::tempdir().unwrap();
+        let fname = dir.path().join("sparse.db");
+        let secret = [0x55u8; 100_000];
+        let keys = {
+            let tab = Mapping::open(&fname).unwrap();
+            let mut keys = vec![];
+            for i in 0u64..1000 {
+                keys.push(tab.put(&i.to_le_bytes()));
+                if i % 100 == 0 {
+                    tab.flush();
+                }
+            }
+            let leaked = tab.put(&secret);
+            tab.flush();
+            tab.remove(leaked);
+            tab.flush();
+            let file_len = std::fs::metadata(&fname).unwrap().len();
+            assert!(tab.punch_garbage().unwrap() > secret.len() as u64);
+            assert_eq!(std::fs::metadata(&fname).unwrap().len(), file_len);
+            for k in keys.iter() {
+                assert!(tab.get(*k).is_some());
+            }
+            // an open transaction keeps what it sees
+            let seen = tab.put(b"seen by a transaction");
+            tab.flush();
+            let tx = tab.transaction();
+            tab.remove(seen);
+            tab.punch_garbage().unwrap();
+            assert_eq!(&tx.get(seen).unwrap()[..], b"seen by a transaction");
+            drop(tx);
+            // garbage ranges are reported again, since they are still unreachable
+            let before = tab.punch_garbage().unwrap();
+            tab.put(b"one more");
+            assert!(tab.punch_garbage().unwrap() > before);
+            keys
+        };
+        let contents = std::fs::read(&fname).unwrap();
+        assert!(!contents.windows(1000).any(|w| w == &secret[..1000]));
+        let tab = Mapping::open(&fname).unwrap();
+        for k in keys.iter() {
+            assert!(tab.get(*k).is_some());
+        }
+
+        // values are never decoded, so one that no longer decompresses is no obstacle
+        let fname = dir.path().join("compressed.db");
+        let options = Options::default().compression(64);
+        {
+            let tab = options.clone().open(&fname).unwrap();
+            tab.put(&b"abcdefgh".repeat(1000));
+            tab.flush();
+        }
+        let mut contents = std::fs::read(&fname).unwrap();
+        let literals = contents.windows(8).position(|w| w == b"abcdefgh").unwrap();
+        contents[literals - 5..literals - 1].copy_from_slice(&1u32.to_le_bytes());
+        std::fs::write(&fname, contents).unwrap();
+        options.open(&fname).unwrap().punch_garbage().unwrap();
+    }
+
+    #[test]
+    fn db_get_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let tab = Mapping::open(dir.path().join("bytes.db")).unwrap();
+        let unflushed = tab.put(b"unflushed");
+        assert_eq!(&tab.get_bytes(unflushed).unwrap()[..], b"unflushed");
+        tab.flush();
+        let flushed = tab.get_bytes(unflushed).unwrap();
+        // zero-copy: the handle points into the same mmap as `get`
+        assert_eq!(flushed.as_ptr(), tab.get(unflushed).unwrap().as_ptr());
+        assert!(tab.get_bytes([0u8; 32]).is_none());
+        drop(tab);
+        let handle = std::thread::spawn(move || flushed.to_vec());
+        assert_eq!(&handle.join().unwrap()[..], b"unflushed");
+    }
+
+    #[test]
+    fn db_get_range() {
+        let dir = tempfile::tempdir().unwrap();
+        // plain records on disk are read in part through either backend; compressed ones are decoded whole
+        for mmap in [true, false] {
+            let tab = Options::default()
+                .mmap(mmap)
+                .compression(4096)
+                .open(dir.path().join(format!("range-{mmap}.db")))
+                .unwrap();
+            let unflushed = tab.put(b"hello world");
+            assert_eq!(tab.value_len(unflushed), Some(11));
+            assert_eq!(&tab.get_range(unflushed, 6..).unwrap()[..], b"world");
+            tab.flush();
+            let flushed = tab.put(b"another value");
+            let large: Vec<u8> = (0..3_000_000u32).map(|i| (i % 251) as u8).collect();
+            let large = tab.put(&large);
+            let compressed = tab.put(&[7u8; 10_000]);
+            tab.flush();
+            for k in [unflushed, flushed, large, compressed] {
+                let v = tab.get(k).unwrap();
+                assert_eq!(tab.value_len(k), Some(v.len() as u64));
+                assert_eq!(&tab.get_range(k, 2..=4).unwrap()[..], &v[2..=4]);
+                assert_eq!(&tab.get_range(k, ..3).unwrap()[..], &v[..3]);
+                assert_eq!(
+                    &tab.get_range(k, 5..1000).unwrap()[..],
+                    &v[5..1000.min(v.len())]
+                );
+                assert_eq!(
+                    &tab.get_range(k, 2_999_000..).unwrap()[..],
+                    &v[2_999_000.min(v.len())..]
+                );
+                assert!(tab.get_range(k, 3_000_000..).unwrap().is_empty());
+            }
+            assert!(tab.value_len([0u8; 32]).is_none());
+            assert!(tab.get_range([0u8; 32], ..).is_none());
+        }
+    }
+
+    #[test]
+    fn db_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let seg_dir = dir.path().join("segmented");
+        let options = Options::default().segment_size(64 * 1024);
+        let keys = {
+            let tab = options.clone().open_dir(&seg_dir).unwrap();
+            let mut keys = vec![];
+            for i in 0u64..300 {
+                keys.push(tab.put(&[i as u8; 1000]));
+                if i % 10 == 0 {
+                    tab.flush();
+                }
+            }
+            tab.flush();
+            keys
+        };
+        let tab = options.open_dir(&seg_dir).unwrap();
+        let segments = tab.segments();
+        assert!(segments.len() > 3);
+        assert_eq!(segments[0], 0);
+        for k in keys.iter() {
+            assert!(tab.get(*k).is_some());
+        }
+        // dropping old segments keeps everything that is still reachable, and what was borrowed from them
+        let borrowed = tab.get(keys[5]).unwrap();
+        let handle = tab.get_bytes(keys[6]).unwrap();
+        // but not while a transaction still sees records in them
+        let before = tab.flushed_root().unwrap();
+        let tx = tab.transaction();
+        assert_eq!(
+            tab.drop_segment(0).unwrap_err().kind(),
+            std::io::ErrorKind::ResourceBusy
+        );
+        assert_eq!(&tx.get(keys[5]).unwrap()[..], &[5u8; 1000]);
+        drop(tx);
+        tab.drop_segment(0).unwrap();
+        tab.drop_segment(1).unwrap();
+        assert_eq!(&borrowed[..], &[5u8; 1000]);
+        assert_eq!(&handle[..], &[6u8; 1000]);
+        assert!(tab.drop_segment(0).is_err());
+        assert!(tab.drop_segment(*tab.segments().last().unwrap()).is_err());
+        assert!(!seg_dir.join("00000000.seg").exists());
+        // nor can roots that reach into dropped segments
+        assert_eq!(
+            tab.diff_since(before).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+        drop(borrowed);
+        drop(tab);
+        assert_eq!(&handle[..], &[6u8; 1000]);
+        let tab = Mapping::open_dir(&seg_dir).unwrap();
+        assert_eq!(tab.segments()[0], 2);
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(&tab.get(*k).unwrap()[..], &[i as u8; 1000]);
+        }
+    }
+
+    #[test]
+    fn db_growing_mmap() {
+        let dir = tempfile::tempdir().unwrap();
+        let tab = Mapping::open(dir.path().join("grow.db")).unwrap();
+        let early = tab.put(b"written while the mmap was small");
+        tab.flush();
+        let borrowed = tab.get(early).unwrap();
+        let shared = tab.get_bytes(early).unwrap();
+        // grow the file well past the initial mmap, forcing several remaps
+        let keys: Vec<_> = (0u32..40)
+            .map(|i| {
+                let k = tab.put(&vec![i as u8; 100_000]);
+                tab.flush();
+                k
+            })
+            .collect();
+        assert_eq!(&borrowed[..], b"written while the mmap was small");
+        assert_eq!(&shared[..], b"written while the mmap was small");
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(&tab.get(*k).unwrap()[..], &vec![i as u8; 100_000][..]);
+        }
+    }
+
+    #[test]
+    fn db_storage_backends() {
+        let dir = tempfile::tempdir().unwrap();
+        let big = vec![0x42u8; 200_000];
+        let pread = Options::default().mmap(false);
+        let check = |tab: &Mapping, keys: &[[u8; 32]]| {
+            for (i, k) in keys.iter().enumerate() {
+                assert_eq!(&tab.get(*k).unwrap()[..], &(i as u64).to_le_bytes());
+            }
+            assert_eq!(
+                &tab.get_bytes(*blake3::hash(&big).as_bytes()).unwrap()[..],
+                &big[..]
+            );
+        };
+        let fill = |tab: &Mapping| {
+            let keys: Vec<_> = (0u64..500).map(|i| tab.put(&i.to_le_bytes())).collect();
+            tab.insert_stream(*blake3::hash(&big).as_bytes(), big.len() as u64, &big[..])
+                .unwrap();
+            tab.flush();
+            keys
+        };
+
+        let mem = Mapping::in_memory();
+        let keys = fill(&mem);
+        check(&mem, &keys);
+        assert!(mem.remove(keys[0]));
+        assert_eq!(mem.purge().unwrap(), 1);
+        assert!(mem.punch_garbage().unwrap() > 0);
+        assert!(mem.get(keys[1]).is_some());
+
+        let keys = fill(&pread.clone().open(dir.path().join("pread.db")).unwrap());
+        check(
+            &pread.clone().open(dir.path().join("pread.db")).unwrap(),
+            &keys,
+        );
+        // files written through one backend can be read through the other
+        check(&Mapping::open(dir.path().join("pread.db")).unwrap(), &keys);
+
+        let segmented = pread.clone().segment_size(16 * 1024);
+        let keys = fill(&segmented.clone().open_dir(dir.path().join("seg")).unwrap());
+        let tab = segmented.open_dir(dir.path().join("seg")).unwrap();
+        check(&tab, &keys);
+        tab.drop_segment(0).unwrap();
+        check(&tab, &keys);
+    }
+
+    #[test]
+    fn db_compression() {
+        let dir = tempfile::tempdir().unwrap();
+        let fname = dir.path().join("compressed.db");
+        let json: Vec<u8> = (0..5000)
+            .flat_map(|i| format!("{{\"id\":{i},\"name\":\"item\"}},").into_bytes())
+            .collect();
+        let rng = fastrand::Rng::with_seed(7);
+        let noise: Vec<u8> = (0..50_000).map(|_| rng.u8(..)).collect();
+        let plain = {
+            let tab = Mapping::open(&fname).unwrap();
+            let plain = tab.put(&json);
+            tab.flush();
+            plain
+        };
+        let (compressed, incompressible, small) = {
+            let tab = Options::default().compression(1024).open(&fname).unwrap();
+            let before = std::fs::metadata(&fname).unwrap().len();
+            let compressed = tab.put(&[&json[..], b"!"].concat());
+            let incompressible = tab.put(&noise);
+            let small = tab.put(b"too short to compress");
+            tab.flush();
+            let grown = std::fs::metadata(&fname).unwrap().len() - before;
+            assert!(grown < (noise.len() + json.len() / 3) as u64);
+            assert_eq!(&tab.get(plain).unwrap()[..], &json[..]);
+            (compressed, incompressible, small)
+        };
+        // compressed and uncompressed records are readable either way
+        let tab = Mapping::open(&fname).unwrap();
+        assert_eq!(&tab.get(plain).unwrap()[..], &json[..]);
+        assert_eq!(
+            &tab.get_bytes(compressed).unwrap()[..],
+            &[&json[..], b"!"].concat()[..]
+        );
+        assert_eq!(tab.value_len(compressed), Some(json.len() as u64 + 1));
+        assert_eq!(
+            &tab.get_verified(incompressible).unwrap().unwrap()[..],
+            &noise[..]
+        );
+        assert_eq!(&tab.get(small).unwrap()[..], b"too short to compress");
+    }
+
+    #[test]
+    fn db_encryption() {
+        let dir = tempfile::tempdir().unwrap();
+        let fname = dir.path().join("encrypted.db");
+        let key = [0x11u8; 32];
+        let secret = b"the launch codes are 0000".repeat(100);
+        let options = Options::default().encryption_key(key).compression(64);
+        let (k, streamed) = {
+            let tab = options.clone().open(&fname).unwrap();
+            let k = tab.put(&secret);
+            let streamed = *blake3::hash(b"streamed secret").as_bytes();
+            tab.insert_stream(streamed, 15, &b"streamed secret"[..])
+                .unwrap();
+            assert_eq!(&tab.get(k).unwrap()[..], &secret[..]);
+            // values too long to be encrypted are rejected up front, rather than failing every flush
+            let err = tab
+                .insert_stream([3u8; 32], 5 << 30, std::io::empty())
+                .unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+            tab.flush();
+            (k, streamed)
+        };
+        let contents = std::fs::read(&fname).unwrap();
+        assert!(!contents.windows(11).any(|w| w == b"launch code"));
+        assert!(!contents.windows(15).any(|w| w == b"streamed secret"));
+
+        for wrong in [
+            Options::default(),
+            Options::default().encryption_key([0x22; 32]),
+        ] {
+            assert_eq!(
+                wrong.open(&fname).err().unwrap().kind(),
+                std::io::ErrorKind::PermissionDenied
+            );
+        }
+        let tab = options.clone().open(&fname).unwrap();
+        assert_eq!(&tab.get_verified(k).unwrap().unwrap()[..], &secret[..]);
+        assert_eq!(&tab.get(streamed).unwrap()[..], b"streamed secret");
+
+        // compaction keeps values encrypted
+        let compacted = dir.path().join("compacted.db");
+        tab.compact_with(&compacted, |_, _, _| true).unwrap();
+        drop(tab);
+        let contents = std::fs::read(&compacted).unwrap();
+        assert!(!contents.windows(11).any(|w| w == b"launch code"));
+        assert!(Mapping::open(&compacted).is_err());
+        let tab = options.open(&compacted).unwrap();
+        assert_eq!(&tab.get(k).unwrap()[..], &secret[..]);
+    }
+
+    #[test]
+    fn db_authentication() {
+        use crate::record::{Codec, Record};
+
+        let dir = tempfile::tempdir().unwrap();
+        let fname = dir.path().join("authenticated.db");
+        let options = Options::default().authentication_key([0x33; 32]);
+        let (a, b) = {
+            let tab = options.clone().open(&fname).unwrap();
+            let a = tab.put(b"first");
+            tab.flush();
+            let b = tab.put(b"second");
+            tab.flush();
+            (a, b)
+        };
+        let tab = options.clone().open(&fname).unwrap();
+        assert_eq!(&tab.get(a).unwrap()[..], b"first");
+        assert_eq!(&tab.get(b).unwrap()[..], b"second");
+        drop(tab);
+        for wrong in [
+            Options::default(),
+            Options::default().authentication_key([0x44; 32]),
+        ] {
+            assert_eq!(
+                wrong.open(&fname).err().unwrap().kind(),
+                std::io::ErrorKind::PermissionDenied
+            );
+        }
+
+        let contents = std::fs::read(&fname).unwrap();
+        let divider = u128::from_le_bytes(*array_ref![contents, 10, 16]);
+        let tampered = |contents: Vec<u8>| {
+            std::fs::write(&fname, contents).unwrap();
+            options.clone().open(&fname).err().unwrap().kind()
+        };
+
+        // rewriting an old root breaks the chain
+        let first_root = contents
+            .windows(16)
+            .enumerate()
+            .filter(|(_, w)| *w == divider.to_le_bytes())
+            .map(|(p, _)| p)
+            .find(|&p| Record::new_borrowed(&contents[p..], divider).is_ok_and(|r| r.is_root()))
+            .unwrap();
+        let mut rewritten = contents.clone();
+        rewritten[first_root + 32] ^= 1;
+        assert_eq!(tampered(rewritten), std::io::ErrorKind::InvalidData);
+
+        // so does forging a new root without the secret
+        let mut forged = contents.clone();
+        Record::HamtNode(true, 0, vec![])
+            .write_bytes(divider, &Codec::default(), &mut forged)
+            .unwrap();
+        assert_eq!(tampered(forged.clone()), std::io::ErrorKind::InvalidData);
+
+        // even with the check value wiped, so that the database looks like it was never authenticated
+        forged[58..90].fill(0);
+        assert_eq!(tampered(forged), std::io::ErrorKind::InvalidData);
+
+        std::fs::write(&fname, contents).unwrap();
+        let tab = options.clone().open(&fname).unwrap();
+        assert_eq!(&tab.get(b).unwrap()[..], b"second");
+        drop(tab);
+
+        // and so does rewriting the tree below the latest root, here by swapping two children of an interior node
+        let deep = dir.path().join("deep.db");
+        let tab = options.clone().open(&deep).unwrap();
+        for i in 0u32..2000 {
+            tab.put(&i.to_le_bytes());
+        }
+        tab.flush();
+        drop(tab);
+        let mut contents = std::fs::read(&deep).unwrap();
+        let divider = u128::from_le_bytes(*array_ref![contents, 10, 16]);
+        let interior = (4096..contents.len())
+            .filter(|&p| contents[p..].starts_with(&divider.to_le_bytes()))
+            .find(|&p| {
+                Record::new_borrowed(&contents[p..], divider)
+                    .is_ok_and(|r| matches!(r, Record::HamtNode(false, _, ptrs) if ptrs.len() >= 2))
+            })
+            .unwrap();
+        let (first, second) = contents[interior + 40..interior + 56].split_at_mut(8);
+        first.swap_with_slice(second);
+        std::fs::write(&deep, contents).unwrap();
+        assert_eq!(
+            options.clone().open(&deep).err().unwrap().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+
+        // turning authentication on for an existing database must be asked for
+        let plain = dir.path().join("plain.db");
+        let tab = Mapping::open(&plain).unwrap();
+        let c = tab.put(b"third");
+        tab.flush();
+        drop(tab);
+        assert_eq!(
+            options.clone().open(&plain).err().unwrap().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+        let tab = options
+            .clone()
+            .adopt_unauthenticated(true)
+            .open(&plain)
+            .unwrap();
+        tab.put(b"fourth");
+        tab.flush();
+        drop(tab);
+        let tab = options.open(&plain).unwrap();
+        assert_eq!(&tab.get(c).unwrap()[..], b"third");
+    }
+
+    #[test]
+    fn db_root_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let values = (0..2000u32).map(|i| i.to_le_bytes()).collect::<Vec<_>>();
+
+        let forward = Mapping::in_memory();
+        let empty = forward.root_hash();
+        for v in values.iter() {
+            forward.put(v);
+        }
+        // a different order, with some extra keys that come and go, and flushes in between
+        let backward = Mapping::in_memory();
+        for (i, v) in values.iter().enumerate().rev() {
+            backward.put(v);
+            if i % 3 == 0 {
+                let extra = backward.put(&(i as u64).to_le_bytes());
+                if i % 500 == 0 {
+                    backward.flush();
+                }
+                backward.remove(extra);
+            }
+        }
+        assert_ne!(forward.root_hash(), empty);
+        assert_eq!(forward.root_hash(), backward.root_hash());
+        forward.flush();
+        assert_eq!(forward.root_hash(), backward.root_hash());
+
+        // bulk loading gives the same HAMT
+        let built = dir.path().join("built.db");
+        Builder::build(
+            &built,
+            values.iter().map(|v| (*blake3::hash(v).as_bytes(), v)),
+        )
+        .unwrap();
+        assert_eq!(
+            Mapping::open(&built).unwrap().root_hash(),
+            forward.root_hash()
+        );
+
+        backward.put(b"one more");
+        assert_ne!(forward.root_hash(), backward.root_hash());
+
+        // only keys are hashed, so values are never decoded
+        let fname = dir.path().join("compressed.db");
+        let options = Options::default().compression(64);
+        let hash = {
+            let tab = options.clone().open(&fname).unwrap();
+            tab.put(&b"abcdefgh".repeat(1000));
+            tab.flush();
+            tab.root_hash()
+        };
+        let mut contents = std::fs::read(&fname).unwrap();
+        let literals = contents.windows(8).position(|w| w == b"abcdefgh").unwrap();
+        contents[literals - 5..literals - 1].copy_from_slice(&1u32.to_le_bytes());
+        std::fs::write(&fname, contents).unwrap();
+        assert_eq!(options.open(&fname).unwrap().root_hash(), hash);
+    }
+
+    #[test]
+    fn db_proofs() {
+        let tab = Mapping::in_memory();
+        let empty = tab.root_hash();
+        let absent = *blake3::hash(b"absent").as_bytes();
+        assert_eq!(verify_proof(empty, absent, &tab.prove(absent)), Some(false));
+
+        let keys = (0..2000u32)
+            .map(|i| tab.put(&i.to_le_bytes()))
+            .collect::<Vec<_>>();
+        tab.flush();
+        tab.put(b"unflushed");
+        let root_hash = tab.root_hash();
+        for key in keys.iter().step_by(97) {
+            let proof = tab.prove(*key);
+            assert_eq!(verify_proof(root_hash, *key, &proof), Some(true));
+            assert_eq!(Proof::decode(&proof.encode()).as_ref(), Some(&proof));
+            // a proof is only good for its own key and root hash
+            assert_eq!(verify_proof(root_hash, absent, &proof), None);
+            assert_eq!(verify_proof(empty, *key, &proof), None);
+        }
+        // absent keys either land in an empty slot or on some other key's leaf
+        let mut leaves = 0;
+        for i in 0..200u32 {
+            let key = *blake3::hash(format!("absent {i}").as_bytes()).as_bytes();
+            let proof = tab.prove(key);
+            leaves += proof.leaf.is_some() as usize;
+            assert_eq!(verify_proof(root_hash, key, &proof), Some(false));
+            assert_eq!(Proof::decode(&proof.encode()).as_ref(), Some(&proof));
+        }
+        assert!(leaves > 0 && leaves < 200);
+
+        let mut forged = tab.prove(keys[0]);
+        forged.leaf = Some(absent);
+        assert_eq!(verify_proof(root_hash, absent, &forged), None);
+    }
+
+    #[test]
+    fn db_watch() {
+        let tab = Arc::new(Mapping::in_memory());
+        let existing = tab.put(b"existing");
+        tab.watch(existing).try_recv().unwrap();
+
+        let subscription = tab.subscribe();
+        let key = *blake3::hash(b"eventually").as_bytes();
+        let watch = tab.watch(key);
+        assert!(watch.try_recv().is_err());
+        let writer = {
+            let tab = tab.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(50));
+                tab.put(b"existing");
+                tab.put(b"eventually")
+            })
+        };
+        watch.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(&tab.get(key).unwrap()[..], b"eventually");
+        assert_eq!(writer.join().unwrap(), key);
+
+        let mut tx = tab.transaction();
+        tx.insert(*blake3::hash(b"committed").as_bytes(), b"committed")
+            .unwrap();
+        tx.commit();
+        assert_eq!(
+            subscription.try_iter().collect::<Vec<_>>(),
+            vec![key, *blake3::hash(b"committed").as_bytes()]
+        );
+    }
+
+    #[test]
+    fn db_verify_on_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let fname = dir.path().join("bitflip.db");
+        let k = {
+            let tab = Mapping::open(&fname).unwrap();
+            let k = tab.put(b"precious data");
+            tab.flush();
+            k
+        };
+        // flip a bit in the value
+        let mut contents = std::fs::read(&fname).unwrap();
+        let posn = contents
+            .windows(13)
+            .position(|w| w == b"precious data")
+            .unwrap();
+        contents[posn] ^= 1;
+        std::fs::write(&fname, contents).unwrap();
+
+        let tab = Mapping::open(&fname).unwrap();
+        assert_eq!(&tab.get(k).unwrap()[..], b"qrecious data");
+        assert_eq!(
+            tab.get_verified(k).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+    }
 }
@@ -0,0 +1,137 @@
+/// Domain separation between the two kinds of hashes, so that a leaf can never pass for a node.
+const LEAF_TAG: u8 = 0x00;
+const NODE_TAG: u8 = 0x01;
+
+/// The Merkle hash of a data record, which commits to its key. For content-addressed keys, that also commits to the value.
+pub fn leaf_hash(key: &[u8; 32]) -> [u8; 32] {
+    let mut h = blake3::Hasher::new();
+    h.update(&[LEAF_TAG]);
+    h.update(key);
+    *h.finalize().as_bytes()
+}
+
+/// The Merkle hash of a HAMT node, given its bitmap and the hashes of its children, in order. Whether the node is a root does not matter.
+pub fn node_hash(bitmap: u64, children: impl IntoIterator<Item = [u8; 32]>) -> [u8; 32] {
+    let mut h = blake3::Hasher::new();
+    h.update(&[NODE_TAG]);
+    h.update(&bitmap.to_le_bytes());
+    for child in children {
+        h.update(&child);
+    }
+    *h.finalize().as_bytes()
+}
+
+/// A proof that a key is, or is not, in a database with a given root hash. See [verify_proof].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Proof {
+    /// The HAMT nodes on the path to the key's slot, from the root down
+    pub path: Vec<ProofNode>,
+    /// The key of the data record in the key's slot, if the slot is not empty
+    pub leaf: Option<[u8; 32]>,
+}
+
+/// A HAMT node on the path of a [Proof].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProofNode {
+    /// The node's bitmap
+    pub bitmap: u64,
+    /// The hashes of the node's children, in order, leaving out the one on the path
+    pub siblings: Vec<[u8; 32]>,
+}
+
+/// HAMT paths cannot be longer than this, since only the first 128 bits of a key are used.
+const MAX_DEPTH: usize = 128_usize.div_ceil(6);
+
+impl Proof {
+    /// Encodes the proof compactly: the length of the path, a byte saying whether there is a leaf, the leaf's key if there is, then every node's bitmap and sibling hashes.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![self.path.len() as u8, self.leaf.is_some() as u8];
+        if let Some(leaf) = &self.leaf {
+            out.extend_from_slice(leaf);
+        }
+        for node in self.path.iter() {
+            out.extend_from_slice(&node.bitmap.to_le_bytes());
+            for sibling in node.siblings.iter() {
+                out.extend_from_slice(sibling);
+            }
+        }
+        out
+    }
+
+    /// Decodes a proof written by [Proof::encode], returning None if it is malformed.
+    pub fn decode(b: &[u8]) -> Option<Self> {
+        let [len, has_leaf, b @ ..] = b else {
+            return None;
+        };
+        let mut b = b;
+        let leaf = match has_leaf {
+            0 => None,
+            1 => {
+                let (leaf, rest) = b.split_first_chunk::<32>()?;
+                b = rest;
+                Some(*leaf)
+            }
+            _ => return None,
+        };
+        let len = (*len as usize).min(MAX_DEPTH + 1);
+        let mut path = vec![];
+        for depth in 0..len {
+            let (bitmap, rest) = b.split_first_chunk::<8>()?;
+            let bitmap = u64::from_le_bytes(*bitmap);
+            // every node has a child on the path, except for a last node whose slot for the key is empty
+            let mut count = bitmap.count_ones() as usize;
+            if leaf.is_some() || depth + 1 < len {
+                count = count.checked_sub(1)?;
+            }
+            let (siblings, rest) = rest.split_at_checked(count * 32)?;
+            b = rest;
+            path.push(ProofNode {
+                bitmap,
+                siblings: siblings
+                    .chunks_exact(32)
+                    .map(|c| c.try_into().unwrap())
+                    .collect(),
+            });
+        }
+        b.is_empty().then_some(Proof { path, leaf })
+    }
+}
+
+/// Checks a [Proof] about a key against a root hash, as returned by `Mapping::root_hash`, without needing the database. Returns whether the key is present, or None if the proof does not match the root hash.
+pub fn verify_proof(root_hash: [u8; 32], key: [u8; 32], proof: &Proof) -> Option<bool> {
+    if proof.path.is_empty() || proof.path.len() > MAX_DEPTH {
+        return None;
+    }
+    let ikey = u128::from_le_bytes(key[..16].try_into().unwrap());
+    let hindex = |depth: usize| ((ikey >> (6 * depth)) & 0b111111) as u32;
+    // work up from the key's slot, which holds either a leaf or nothing
+    let mut nodes = proof.path.iter().enumerate().rev();
+    let (mut hash, present) = match proof.leaf {
+        Some(leaf) => (leaf_hash(&leaf), leaf == key),
+        None => {
+            let (depth, node) = nodes.next()?;
+            if (node.bitmap >> hindex(depth)) & 1 == 1
+                || node.siblings.len() != node.bitmap.count_ones() as usize
+            {
+                return None;
+            }
+            (node_hash(node.bitmap, node.siblings.iter().copied()), false)
+        }
+    };
+    for (depth, node) in nodes {
+        let hindex = hindex(depth);
+        if (node.bitmap >> hindex) & 1 == 0
+            || node.siblings.len() + 1 != node.bitmap.count_ones() as usize
+        {
+            return None;
+        }
+        let idx = (node.bitmap & ((1 << hindex) - 1)).count_ones() as usize;
+        let children = node.siblings[..idx]
+            .iter()
+            .copied()
+            .chain(std::iter::once(hash))
+            .chain(node.siblings[idx..].iter().copied());
+        hash = node_hash(node.bitmap, children);
+    }
+    (hash == root_hash).then_some(present)
+}
@@ -1,7 +1,12 @@
-use std::{borrow::Cow, hash::Hasher, io::Write, sync::Arc};
+use std::{borrow::Cow, hash::Hasher, sync::Arc};
 
 use arrayref::array_ref;
+use chacha20poly1305::{
+    Key, KeyInit, XChaCha20Poly1305, XNonce,
+    aead::{Aead, Payload},
+};
 use siphasher::sip::SipHasher13;
+use zeroize::Zeroizing;
 
 /// An on-disk --- or in-memory --- database record.
 #[derive(Debug, Clone)]
@@ -15,26 +20,150 @@ pub enum Record<'a> {
 const RECORD_KIND_DATA: u32 = 0x00;
 const RECORD_KIND_HAMI: u32 = 0x01;
 const RECORD_KIND_HAMR: u32 = 0x02;
+const RECORD_KIND_DATA_LARGE: u32 = 0x03;
+const RECORD_KIND_DATA_FLAGGED: u32 = 0x04;
+const RECORD_KIND_HAMR_AUTH: u32 = 0x05;
+
+/// Length of the trailer of an authenticated root: the pointer to the authenticated root before it, that root's MAC, the Merkle hash of the tree below this root, and this root's MAC.
+const ROOT_AUTH_LEN: usize = 8 + 32 + 32 + 32;
+
+/// Flag of a flagged data record: the value is an LZ4 block, prefixed with its uncompressed length.
+const DATA_FLAG_COMPRESSED: u8 = 0x01;
+/// Flag of a flagged data record: the value is encrypted with XChaCha20-Poly1305, prefixed with its nonce. This is applied after compression.
+const DATA_FLAG_ENCRYPTED: u8 = 0x02;
+
+/// Encrypted values must fit a flagged data record, whose length is 32 bits wide, along with the key, the flags, the nonce and the tag.
+pub const MAX_ENCRYPTED_LEN: u64 = u32::MAX as u64 - 32 - 1 - 24 - 16;
 
 const RECORD_HEADER_SIZE: usize = 16;
+const RECORD_HEADER_SIZE_LARGE: usize = 20;
+
+/// How much of a record [Record::encoded_len] needs to see.
+pub const MAX_PREFIX_LEN: usize = 16 + RECORD_HEADER_SIZE_LARGE;
+
+/// How data records are encoded when written. Records are always readable, whatever encoding they were written with, except that encrypted records need the key.
+#[derive(Clone, Default)]
+pub struct Encoding {
+    /// Values at least this long are compressed, if that makes them shorter.
+    pub compress_threshold: Option<usize>,
+    /// The key that values are encrypted with.
+    pub key: Option<Zeroizing<[u8; 32]>>,
+    /// The secret that roots are authenticated with.
+    pub auth_key: Option<Zeroizing<[u8; 32]>>,
+}
+
+/// An [Encoding], bound to the divider of one database.
+#[derive(Default)]
+pub struct Codec {
+    compress_threshold: Option<usize>,
+    /// The cipher, keyed from the encryption key with the divider as salt. It zeroizes its key on drop.
+    cipher: Option<XChaCha20Poly1305>,
+    /// A value derived from the encryption key and the divider, stored in the reserved region so that a wrong key is caught on open.
+    key_check: Option<[u8; 32]>,
+    /// The key that roots are MACed with, derived from the authentication secret with the divider as salt.
+    root_key: Option<Zeroizing<[u8; 32]>>,
+    /// Like `key_check`, but for the authentication secret.
+    auth_check: Option<[u8; 32]>,
+}
+
+impl Codec {
+    /// Binds an encoding to a database's divider.
+    pub fn new(encoding: &Encoding, divider: u128) -> Self {
+        let mut codec = Codec {
+            compress_threshold: encoding.compress_threshold,
+            ..Default::default()
+        };
+        if let Some(key) = &encoding.key {
+            let mut material = Zeroizing::new([0u8; 48]);
+            material[..32].copy_from_slice(&key[..]);
+            material[32..].copy_from_slice(&divider.to_le_bytes());
+            let value_key = Zeroizing::new(blake3::derive_key(
+                "meshanina 2024-06 value encryption",
+                &material[..],
+            ));
+            codec.cipher = Some(XChaCha20Poly1305::new(Key::from_slice(&value_key[..])));
+            codec.key_check = Some(blake3::derive_key(
+                "meshanina 2024-06 key check",
+                &material[..],
+            ));
+        }
+        if let Some(secret) = &encoding.auth_key {
+            let mut material = Zeroizing::new([0u8; 48]);
+            material[..32].copy_from_slice(&secret[..]);
+            material[32..].copy_from_slice(&divider.to_le_bytes());
+            codec.root_key = Some(Zeroizing::new(blake3::derive_key(
+                "meshanina 2024-06 root authentication",
+                &material[..],
+            )));
+            codec.auth_check = Some(blake3::derive_key(
+                "meshanina 2024-06 authentication check",
+                &material[..],
+            ));
+        }
+        codec
+    }
+
+    /// Whether values are encrypted.
+    pub fn encrypts(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    /// Fails with [std::io::ErrorKind::InvalidInput] if a value of the given length could not be written, since it is too long to be encrypted.
+    pub fn check_value_len(&self, len: u64) -> std::io::Result<()> {
+        if self.cipher.is_some() && len > MAX_ENCRYPTED_LEN {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "values longer than 4 GiB cannot be encrypted",
+            ));
+        }
+        Ok(())
+    }
+
+    /// The value stored in the reserved region to check the encryption key, if there is one.
+    pub fn key_check(&self) -> Option<[u8; 32]> {
+        self.key_check
+    }
+
+    /// The key that roots are MACed with, if roots are authenticated.
+    pub fn root_key(&self) -> Option<&[u8; 32]> {
+        self.root_key.as_deref()
+    }
+
+    /// The value stored in the reserved region to check the authentication secret, if there is one.
+    pub fn auth_check(&self) -> Option<[u8; 32]> {
+        self.auth_check
+    }
+}
+
+/// The trailer of an authenticated root, which chains it to the authenticated root written before it.
+#[derive(Debug, Clone, Copy)]
+pub struct RootAuth {
+    /// Where the authenticated root before this one is, if there was one
+    pub prev: Option<u64>,
+    /// The MAC of the authenticated root before this one, or zeros
+    pub prev_mac: [u8; 32],
+    /// The Merkle hash of the tree below this root, as computed by `Table::root_hash`
+    pub merkle: [u8; 32],
+    /// The MAC of this root, over its bitmap, its pointers and the three fields above
+    pub mac: [u8; 32],
+}
 
 impl<'a> Record<'a> {
     /// Borrows an mmapped, on-disk record, given a slice that *starts* at the correct offset. Returns None if the record is malformed in any way. The slice given should start *at* the "magic divider", which must be passed in.
     pub fn new_borrowed(b: &'a [u8], divider: u128) -> anyhow::Result<Self> {
-        if b.len() < 16 + 16 {
-            anyhow::bail!("not long enough");
-        }
-        if u128::from_le_bytes(*array_ref![b, 0, 16]) != divider {
-            anyhow::bail!("divider not found");
-        }
+        Ok(Self::parse(b, divider, &Codec::default())?.0)
+    }
+
+    /// Like [Record::new_borrowed], but also returns the length of the whole encoded record, divider included. Values are decoded with the given [Codec].
+    pub fn parse(b: &'a [u8], divider: u128, codec: &Codec) -> anyhow::Result<(Self, usize)> {
+        let (record_kind, record_length, header_size) = parse_header(b, divider)?;
         let b = &b[16..];
-        let checksum = u64::from_le_bytes(*array_ref![b, 0, 8]);
-        let record_kind = u32::from_le_bytes(*array_ref![b, 8, 4]);
-        let record_length = u32::from_le_bytes(*array_ref![b, 8 + 4, 4]) as usize;
-        if b.len() < (record_length + RECORD_HEADER_SIZE) as usize {
+        if b.len() - header_size < record_length {
             anyhow::bail!("not long enough");
         }
-        if record_kind == RECORD_KIND_HAMR {
+        let checksum = u64::from_le_bytes(*array_ref![b, 0, 8]);
+        let encoded_len = 16 + header_size + record_length;
+        if record_kind == RECORD_KIND_HAMR || record_kind == RECORD_KIND_HAMR_AUTH {
             let computed_checksum = {
                 let mut h = SipHasher13::new_with_key(&divider.to_le_bytes());
                 h.write(&b[8..][..record_length + 8]);
@@ -45,17 +174,57 @@ impl<'a> Record<'a> {
             }
         }
         match record_kind {
-            RECORD_KIND_DATA => {
-                let key_and_val = &b[RECORD_HEADER_SIZE..][..record_length];
+            RECORD_KIND_DATA | RECORD_KIND_DATA_LARGE => {
+                let key_and_val = &b[header_size..][..record_length];
                 if key_and_val.len() < 32 {
                     anyhow::bail!("key_and_val not long enough");
                 }
                 let key = *array_ref![key_and_val, 0, 32];
                 let val = Cow::Borrowed(&key_and_val[32..]);
-                Ok(Self::Data(key, val))
+                Ok((Self::Data(key, val), encoded_len))
+            }
+            RECORD_KIND_DATA_FLAGGED => {
+                let key_and_val = &b[header_size..][..record_length];
+                if key_and_val.len() < 33 {
+                    anyhow::bail!("key_and_val not long enough");
+                }
+                let key = *array_ref![key_and_val, 0, 32];
+                let flags = key_and_val[32];
+                if flags & !(DATA_FLAG_COMPRESSED | DATA_FLAG_ENCRYPTED) != 0 {
+                    anyhow::bail!("unknown data record flags {flags:#x}");
+                }
+                let mut val = Cow::Borrowed(&key_and_val[33..]);
+                if flags & DATA_FLAG_ENCRYPTED != 0 {
+                    let Some(cipher) = &codec.cipher else {
+                        anyhow::bail!("value is encrypted, but no key was given");
+                    };
+                    if val.len() < 24 {
+                        anyhow::bail!("encrypted value not long enough");
+                    }
+                    let plaintext = cipher
+                        .decrypt(
+                            XNonce::from_slice(&val[..24]),
+                            Payload {
+                                msg: &val[24..],
+                                aad: &key,
+                            },
+                        )
+                        .map_err(|_| anyhow::anyhow!("value failed to decrypt"))?;
+                    val = Cow::Owned(plaintext);
+                }
+                if flags & DATA_FLAG_COMPRESSED != 0 {
+                    val = Cow::Owned(lz4_flex::decompress_size_prepended(&val)?);
+                }
+                Ok((Self::Data(key, val), encoded_len))
             }
-            RECORD_KIND_HAMI | RECORD_KIND_HAMR => {
-                let hamt_raw = &b[RECORD_HEADER_SIZE..][..record_length];
+            RECORD_KIND_HAMI | RECORD_KIND_HAMR | RECORD_KIND_HAMR_AUTH => {
+                let mut hamt_raw = &b[RECORD_HEADER_SIZE..][..record_length];
+                if record_kind == RECORD_KIND_HAMR_AUTH {
+                    if hamt_raw.len() < ROOT_AUTH_LEN {
+                        anyhow::bail!("authenticated root not long enough");
+                    }
+                    hamt_raw = &hamt_raw[..hamt_raw.len() - ROOT_AUTH_LEN];
+                }
                 if hamt_raw.len() < 8 {
                     anyhow::bail!("hamt not long enough");
                 }
@@ -69,81 +238,199 @@ impl<'a> Record<'a> {
                     .map(|ch| u64::from_le_bytes(*array_ref![ch, 0, 8]))
                     .map(RecordPtr::OnDisk)
                     .collect();
-                Ok(Self::HamtNode(
-                    record_kind == RECORD_KIND_HAMR,
-                    hamt_bitmap,
-                    ptrs,
+                Ok((
+                    Self::HamtNode(record_kind != RECORD_KIND_HAMI, hamt_bitmap, ptrs),
+                    encoded_len,
                 ))
             }
             _ => anyhow::bail!("corrupt record kind"),
         }
     }
 
+    /// Works out the length of a whole encoded record, divider included, from a slice that starts at the divider and holds at least [MAX_PREFIX_LEN] bytes of it, or runs to the end of the log.
+    pub fn encoded_len(b: &[u8], divider: u128) -> anyhow::Result<usize> {
+        let (_, record_length, header_size) = parse_header(b, divider)?;
+        Ok(16 + header_size + record_length)
+    }
+
+    /// Reads the key of a data record of any kind, without its value, from a slice that starts at the divider and holds at least [MAX_PREFIX_LEN] + 32 bytes of the record, or runs to the end of the log. Returns None if the record is a HAMT node.
+    pub fn data_key(b: &[u8], divider: u128) -> anyhow::Result<Option<[u8; 32]>> {
+        let (record_kind, record_length, header_size) = parse_header(b, divider)?;
+        if !matches!(
+            record_kind,
+            RECORD_KIND_DATA | RECORD_KIND_DATA_LARGE | RECORD_KIND_DATA_FLAGGED
+        ) {
+            return Ok(None);
+        }
+        let key_start = 16 + header_size;
+        if record_length < 32 || b.len() < key_start + 32 {
+            anyhow::bail!("key_and_val not long enough");
+        }
+        Ok(Some(*array_ref![b, key_start, 32]))
+    }
+
+    /// Finds the value of a plain data record, stored as is, without reading it. Takes a slice that starts at the divider and holds at least [MAX_PREFIX_LEN] + 32 bytes of the record, or runs to the end of the log. Returns the key, where the value starts relative to the divider and how long it is, or None if the record is of any other kind.
+    pub fn plain_data(b: &[u8], divider: u128) -> anyhow::Result<Option<([u8; 32], usize, usize)>> {
+        let (record_kind, record_length, header_size) = parse_header(b, divider)?;
+        if record_kind != RECORD_KIND_DATA && record_kind != RECORD_KIND_DATA_LARGE {
+            return Ok(None);
+        }
+        let key_start = 16 + header_size;
+        if record_length < 32 || b.len() < key_start + 32 {
+            anyhow::bail!("key_and_val not long enough");
+        }
+        Ok(Some((
+            *array_ref![b, key_start, 32],
+            key_start + 32,
+            record_length - 32,
+        )))
+    }
+
+    /// Reads the trailer of an authenticated root, given a slice that starts at its divider, and checks its MAC with the given key. Returns None if the record is a root that is not authenticated.
+    pub fn root_auth(
+        b: &[u8],
+        divider: u128,
+        root_key: &[u8; 32],
+    ) -> anyhow::Result<Option<RootAuth>> {
+        let (record, _) = Record::parse(b, divider, &Codec::default())?;
+        if !record.is_root() {
+            anyhow::bail!("not a root");
+        }
+        let (record_kind, record_length, header_size) = parse_header(b, divider)?;
+        if record_kind != RECORD_KIND_HAMR_AUTH {
+            return Ok(None);
+        }
+        let content = &b[16 + header_size..][..record_length];
+        let (signed, mac) = content.split_at(record_length - 32);
+        let trailer = &signed[signed.len() - (ROOT_AUTH_LEN - 32)..];
+        if blake3::keyed_hash(root_key, signed) != blake3::Hash::from_bytes(*array_ref![mac, 0, 32])
+        {
+            anyhow::bail!("root MAC mismatch");
+        }
+        let prev = u64::from_le_bytes(*array_ref![trailer, 0, 8]);
+        Ok(Some(RootAuth {
+            prev: (prev != u64::MAX).then_some(prev),
+            prev_mac: *array_ref![trailer, 8, 32],
+            merkle: *array_ref![trailer, 40, 32],
+            mac: *array_ref![mac, 0, 32],
+        }))
+    }
+
     /// Checks whether this is a root.
     pub fn is_root(&self) -> bool {
         matches!(self, Record::HamtNode(true, _, _))
     }
 
-    /// Writes the bytes representation of this record, returning how many bytes were written. Must provide a u128 divider.
+    /// Writes the bytes representation of this record, returning how many bytes were written. Must provide a u128 divider, and the [Codec] to encode data records with.
     ///
     /// Will panic if this is a HAMT node with in-memory children!
     pub fn write_bytes(
         &self,
         divider: u128,
-        mut out: impl std::io::Write,
+        codec: &Codec,
+        out: impl std::io::Write,
     ) -> std::io::Result<usize> {
-        let mut null_checksum_buffer = Vec::with_capacity(512);
-        // write the divider
-        null_checksum_buffer.write_all(&divider.to_le_bytes())?;
-        // write a DUMMY checksum
-        null_checksum_buffer.write_all(&[0u8; 8])?;
-        // write the kind
-        let kind = match self {
-            Record::Data(_, _) => RECORD_KIND_DATA,
-            Record::HamtNode(true, _, _) => RECORD_KIND_HAMR,
-            Record::HamtNode(false, _, _) => RECORD_KIND_HAMI,
-        };
-        null_checksum_buffer.write_all(&kind.to_le_bytes())?;
-        // write the length
-        let length = match self {
-            Record::Data(_, v) => v.len() + 32,
-            Record::HamtNode(_, _, ptrs) => ptrs.len() * 8 + 8,
-        };
-        null_checksum_buffer.write_all(&(length as u32).to_le_bytes())?;
-        // write the record
-        match self {
+        // HAMT nodes and transformed values are serialized up front. Other data values are written straight from where they are, without copying.
+        let buffer;
+        let mut flags = 0;
+        let (kind, content): (u32, [&[u8]; 3]) = match self {
             Record::Data(k, v) => {
-                null_checksum_buffer.write_all(k)?;
-                null_checksum_buffer.write_all(v)?;
-            }
-            Record::HamtNode(_, bmap, ptrs) => {
-                null_checksum_buffer.write_all(&bmap.to_le_bytes())?;
-                for ptr in ptrs.iter() {
-                    match ptr {
-                        RecordPtr::InMemory(_) => {
-                            panic!("cannot serialize a HAMT node that has in-memory children")
-                        }
-                        RecordPtr::OnDisk(ptr) => {
-                            null_checksum_buffer.write_all(&ptr.to_le_bytes())?
-                        }
+                let mut value = Cow::Borrowed(&v[..]);
+                // the uncompressed length is prepended as 32 bits, so larger values are never compressed
+                if codec
+                    .compress_threshold
+                    .is_some_and(|threshold| v.len() >= threshold)
+                    && v.len() <= u32::MAX as usize
+                {
+                    let compressed = lz4_flex::compress_prepend_size(v);
+                    if compressed.len() < v.len() {
+                        value = Cow::Owned(compressed);
+                        flags |= DATA_FLAG_COMPRESSED;
                     }
                 }
+                if let Some(cipher) = &codec.cipher {
+                    let mut nonce = [0u8; 24];
+                    getrandom::fill(&mut nonce).unwrap();
+                    let ciphertext = cipher
+                        .encrypt(
+                            XNonce::from_slice(&nonce),
+                            Payload {
+                                msg: &value,
+                                aad: k,
+                            },
+                        )
+                        .map_err(|_| std::io::Error::other("value failed to encrypt"))?;
+                    value = Cow::Owned([&nonce[..], &ciphertext].concat());
+                    flags |= DATA_FLAG_ENCRYPTED;
+                }
+                if value.len() + 33 > u32::MAX as usize {
+                    if flags & DATA_FLAG_ENCRYPTED != 0 {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            "values longer than 4 GiB cannot be encrypted",
+                        ));
+                    }
+                    (RECORD_KIND_DATA_LARGE, [k, v, &[]])
+                } else if flags != 0 {
+                    buffer = value.into_owned();
+                    (
+                        RECORD_KIND_DATA_FLAGGED,
+                        [k, std::slice::from_ref(&flags), &buffer],
+                    )
+                } else {
+                    (RECORD_KIND_DATA, [k, v, &[]])
+                }
+            }
+            Record::HamtNode(is_root, _, _) => {
+                buffer = self.hamt_content();
+                let kind = if *is_root {
+                    RECORD_KIND_HAMR
+                } else {
+                    RECORD_KIND_HAMI
+                };
+                (kind, [&buffer, &[], &[]])
             }
-        }
-        // compute checksum
-        let checksum = {
-            let mut h = SipHasher13::new_with_key(&divider.to_le_bytes());
-            h.write(&null_checksum_buffer[8 + 16..]);
-            h.finish()
         };
-        null_checksum_buffer[16..][..8].copy_from_slice(&checksum.to_le_bytes());
-        // null_checksum_buffer.resize(
-        //     null_checksum_buffer.len() + ((8 - null_checksum_buffer.len() % 8) % 8),
-        //     0xff,
-        // );
-        // return
-        out.write_all(&null_checksum_buffer)?;
-        Ok(null_checksum_buffer.len())
+        write_raw(divider, kind, content, out)
+    }
+
+    /// Writes this root as an authenticated root, chained to the authenticated root before it, if there was one, and committed to the given Merkle hash of the tree below it. Returns how many bytes were written, and the MAC of the new root.
+    ///
+    /// Will panic if this is not a root, or has in-memory children!
+    pub fn write_authenticated_root(
+        &self,
+        divider: u128,
+        root_key: &[u8; 32],
+        prev: Option<(u64, [u8; 32])>,
+        merkle: &[u8; 32],
+        out: impl std::io::Write,
+    ) -> std::io::Result<(usize, [u8; 32])> {
+        assert!(self.is_root(), "only roots can be authenticated");
+        let mut signed = self.hamt_content();
+        let (prev_ptr, prev_mac) = prev.unwrap_or((u64::MAX, [0u8; 32]));
+        signed.extend_from_slice(&prev_ptr.to_le_bytes());
+        signed.extend_from_slice(&prev_mac);
+        signed.extend_from_slice(merkle);
+        let mac = *blake3::keyed_hash(root_key, &signed).as_bytes();
+        let n = write_raw(divider, RECORD_KIND_HAMR_AUTH, [&signed, &mac, &[]], out)?;
+        Ok((n, mac))
+    }
+
+    /// Serializes the bitmap and pointers of a HAMT node.
+    fn hamt_content(&self) -> Vec<u8> {
+        let Record::HamtNode(_, bmap, ptrs) = self else {
+            panic!("not a HAMT node")
+        };
+        let mut buffer = bmap.to_le_bytes().to_vec();
+        for ptr in ptrs.iter() {
+            match ptr {
+                RecordPtr::InMemory(_) => {
+                    panic!("cannot serialize a HAMT node that has in-memory children")
+                }
+                RecordPtr::OnDisk(ptr) => buffer.extend_from_slice(&ptr.to_le_bytes()),
+            }
+        }
+        buffer
     }
 
     /// Fully own the record.
@@ -168,6 +455,139 @@ impl<'a> Record<'a> {
     }
 }
 
+/// A large data record whose value, exactly `length` bytes long, is written piece by piece, without ever being buffered whole. The checksum comes before the value, so the part of the record before the value, from [DataStream::finish], is only known once all of the value has gone through [DataStream::update].
+pub struct DataStream {
+    divider: u128,
+    key: [u8; 32],
+    length: u64,
+    written: u64,
+    hasher: SipHasher13,
+}
+
+impl DataStream {
+    /// Where the value starts within the record.
+    pub const VALUE_OFFSET: u64 = (16 + RECORD_HEADER_SIZE_LARGE + 32) as u64;
+
+    /// Starts a large data record.
+    pub fn new(divider: u128, key: [u8; 32], length: u64) -> Self {
+        let mut hasher = SipHasher13::new_with_key(&divider.to_le_bytes());
+        hasher.write(&record_header(RECORD_KIND_DATA_LARGE, length + 32));
+        hasher.write(&key);
+        Self {
+            divider,
+            key,
+            length,
+            written: 0,
+            hasher,
+        }
+    }
+
+    /// The length of the whole encoded record, divider included.
+    pub fn encoded_len(&self) -> u64 {
+        Self::VALUE_OFFSET + self.length
+    }
+
+    /// How much of the value has gone through so far.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Adds the next piece of the value to the checksum. Fails if the value gets longer than the given length.
+    pub fn update(&mut self, piece: &[u8]) -> std::io::Result<()> {
+        if self.written + piece.len() as u64 > self.length {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "value longer than the given length",
+            ));
+        }
+        self.hasher.write(piece);
+        self.written += piece.len() as u64;
+        Ok(())
+    }
+
+    /// Returns the part of the record before the value. Fails if the value is shorter than the given length.
+    pub fn finish(&self) -> std::io::Result<Vec<u8>> {
+        if self.written != self.length {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "value shorter than the given length",
+            ));
+        }
+        let mut out = self.divider.to_le_bytes().to_vec();
+        out.extend_from_slice(&self.hasher.finish().to_le_bytes());
+        out.extend_from_slice(&record_header(RECORD_KIND_DATA_LARGE, self.length + 32));
+        out.extend_from_slice(&self.key);
+        Ok(out)
+    }
+}
+
+/// Writes a record of the given kind, with its content given in pieces, returning how many bytes were written.
+fn write_raw(
+    divider: u128,
+    kind: u32,
+    content: [&[u8]; 3],
+    mut out: impl std::io::Write,
+) -> std::io::Result<usize> {
+    let length = content.iter().map(|c| c.len() as u64).sum();
+    let header = record_header(kind, length);
+    // compute checksum
+    let checksum = {
+        let mut h = SipHasher13::new_with_key(&divider.to_le_bytes());
+        h.write(&header);
+        for c in content {
+            h.write(c);
+        }
+        h.finish()
+    };
+    out.write_all(&divider.to_le_bytes())?;
+    out.write_all(&checksum.to_le_bytes())?;
+    out.write_all(&header)?;
+    for c in content {
+        out.write_all(c)?;
+    }
+    Ok(16 + 8 + header.len() + length as usize)
+}
+
+/// Parses the divider and header of a record, returning the kind, the length of the content and the length of the header after the divider.
+fn parse_header(b: &[u8], divider: u128) -> anyhow::Result<(u32, usize, usize)> {
+    if b.len() < 16 + 16 {
+        anyhow::bail!("not long enough");
+    }
+    if u128::from_le_bytes(*array_ref![b, 0, 16]) != divider {
+        anyhow::bail!("divider not found");
+    }
+    let b = &b[16..];
+    let record_kind = u32::from_le_bytes(*array_ref![b, 8, 4]);
+    if record_kind == RECORD_KIND_DATA_LARGE {
+        if b.len() < RECORD_HEADER_SIZE_LARGE {
+            anyhow::bail!("not long enough");
+        }
+        let record_length = u64::from_le_bytes(*array_ref![b, 8 + 4, 8]);
+        Ok((
+            record_kind,
+            usize::try_from(record_length)?,
+            RECORD_HEADER_SIZE_LARGE,
+        ))
+    } else {
+        Ok((
+            record_kind,
+            u32::from_le_bytes(*array_ref![b, 8 + 4, 4]) as usize,
+            RECORD_HEADER_SIZE,
+        ))
+    }
+}
+
+/// Encodes the part of a record header after the checksum: the kind, then the length, which is 64 bits wide for large data records and 32 bits wide otherwise.
+fn record_header(kind: u32, length: u64) -> Vec<u8> {
+    let mut header = kind.to_le_bytes().to_vec();
+    if kind == RECORD_KIND_DATA_LARGE {
+        header.extend_from_slice(&length.to_le_bytes());
+    } else {
+        header.extend_from_slice(&(length as u32).to_le_bytes());
+    }
+    header
+}
+
 /// A pointer to another record, either in-memory on on-disk.
 #[derive(Clone, Debug)]
 pub enum RecordPtr<'a> {
@@ -0,0 +1,198 @@
+use std::io::{BufReader, BufWriter, Read, Write};
+
+use arrayref::array_ref;
+
+use crate::Mapping;
+
+/// Frame of the leader's reply that ends it, followed by the leader's database identifier and new high-water mark.
+const FRAME_END: u8 = 0x00;
+/// Frame of the leader's reply that carries a key-value pair: the key, the length of the value as 8 little-endian bytes, then the value.
+const FRAME_RECORD: u8 = 0x01;
+/// Frame of the leader's reply that turns the follower away, since its cursor came from a different database.
+const FRAME_MISMATCH: u8 = 0x02;
+
+/// Where a follower is in a leader's log, as returned by [Mapping::replicate_from]. The default cursor is for the first round.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReplicationCursor {
+    /// The identifier of the leader's database, or zeros before the first round
+    pub database: [u8; 32],
+    /// The leader's high-water mark
+    pub offset: u64,
+}
+
+impl ReplicationCursor {
+    /// Encodes the cursor as it is sent: the database identifier, then the high-water mark as 8 little-endian bytes.
+    fn encode(&self) -> [u8; 40] {
+        let mut out = [0u8; 40];
+        out[..32].copy_from_slice(&self.database);
+        out[32..].copy_from_slice(&self.offset.to_le_bytes());
+        out
+    }
+
+    /// Decodes a cursor written by [ReplicationCursor::encode].
+    fn decode(b: &[u8; 40]) -> Self {
+        Self {
+            database: *array_ref![b, 0, 32],
+            offset: u64::from_le_bytes(*array_ref![b, 32, 8]),
+        }
+    }
+}
+
+/// The error for a round between a follower and a leader that its cursor did not come from.
+fn mismatch() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "replication cursor belongs to a different database",
+    )
+}
+
+impl Mapping {
+    /// Serves one round of replication to a follower on the other end of a stream, which runs [Mapping::replicate_from]. The follower sends the [ReplicationCursor] it got from the previous round; the leader flushes, then replies with every key-value pair that is still present and was written to its log since then, up to any value it is still streaming in, followed by a new cursor. Returns how many pairs were sent.
+    ///
+    /// A cursor from a different database is turned away, failing with [std::io::ErrorKind::InvalidData] on both ends, since its high-water mark means nothing here.
+    pub fn serve_replica(&self, mut stream: impl Read + Write) -> std::io::Result<u64> {
+        let mut cursor = [0u8; 40];
+        stream.read_exact(&mut cursor)?;
+        let cursor = ReplicationCursor::decode(&cursor);
+        let database = self.inner.read().database_id();
+        if cursor.database != [0; 32] && cursor.database != database {
+            stream.write_all(&[FRAME_MISMATCH])?;
+            stream.flush()?;
+            return Err(mismatch());
+        }
+        // only flushed pairs are in the log
+        self.flush();
+        let mut changes = self.changes_since(cursor.offset);
+        let mut out = BufWriter::new(&mut stream);
+        let mut sent = 0;
+        for (key, value) in changes.by_ref() {
+            if self.value_len(key).is_none() {
+                // removed since it was written
+                continue;
+            }
+            out.write_all(&[FRAME_RECORD])?;
+            out.write_all(&key)?;
+            out.write_all(&(value.len() as u64).to_le_bytes())?;
+            out.write_all(&value)?;
+            sent += 1;
+        }
+        out.write_all(&[FRAME_END])?;
+        let cursor = ReplicationCursor {
+            database,
+            offset: changes.cursor(),
+        };
+        out.write_all(&cursor.encode())?;
+        out.flush()?;
+        Ok(sent)
+    }
+
+    /// Pulls one round of replication from a leader on the other end of a stream, which runs [Mapping::serve_replica], inserting every pair the leader sends. `cursor` is the one returned by the previous round, or the default the first time; the new one is returned once everything received is flushed, and should be kept for the next round. A follower must only ever pull from the one leader its cursor came from; any other fails with [std::io::ErrorKind::InvalidData].
+    ///
+    /// Removals on the leader are not replicated. In strict mode, a pair whose key is not the hash of its value fails the round.
+    pub fn replicate_from(
+        &self,
+        mut stream: impl Read + Write,
+        cursor: ReplicationCursor,
+    ) -> std::io::Result<ReplicationCursor> {
+        stream.write_all(&cursor.encode())?;
+        stream.flush()?;
+        let mut stream = BufReader::new(stream);
+        loop {
+            let mut frame = [0u8];
+            stream.read_exact(&mut frame)?;
+            match frame[0] {
+                FRAME_RECORD => {
+                    let mut key = [0u8; 32];
+                    stream.read_exact(&mut key)?;
+                    let mut length = [0u8; 8];
+                    stream.read_exact(&mut length)?;
+                    let length = u64::from_le_bytes(length);
+                    // values may be large, so they are streamed in rather than buffered
+                    let mut value = (&mut stream).take(length);
+                    self.insert_stream(key, length, &mut value)?;
+                    // a key that is already here is not read at all, but its value must still be skipped
+                    std::io::copy(&mut value, &mut std::io::sink())?;
+                    if value.limit() != 0 {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::UnexpectedEof,
+                            "replication stream ended in the middle of a value",
+                        ));
+                    }
+                }
+                FRAME_END => {
+                    let mut next = [0u8; 40];
+                    stream.read_exact(&mut next)?;
+                    let next = ReplicationCursor::decode(&next);
+                    self.flush();
+                    if cursor.database != [0; 32] && cursor.database != next.database {
+                        return Err(mismatch());
+                    }
+                    return Ok(next);
+                }
+                FRAME_MISMATCH => return Err(mismatch()),
+                _ => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "unknown replication frame",
+                    ));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn replicate_over_socket() {
+        use std::os::unix::net::UnixStream;
+
+        let leader = Mapping::in_memory();
+        let follower = Mapping::in_memory();
+        let round = |leader: &Mapping, cursor| {
+            let (a, b) = UnixStream::pair().unwrap();
+            std::thread::scope(|s| {
+                let served = s.spawn(|| leader.serve_replica(a));
+                let cursor = follower.replicate_from(b, cursor);
+                (served.join().unwrap(), cursor)
+            })
+        };
+
+        for i in 0..1000u32 {
+            leader.put(&i.to_le_bytes());
+        }
+        leader.put(&vec![7u8; 1 << 20]);
+        let removed = leader.put(b"removed");
+        leader.remove(removed);
+        let (sent, cursor) = round(&leader, ReplicationCursor::default());
+        let cursor = cursor.unwrap();
+        assert_eq!(sent.unwrap(), 1001);
+        assert_eq!(follower.root_hash(), leader.root_hash());
+
+        // starting over sends everything again, and what the follower already has is skipped
+        let (sent, again) = round(&leader, ReplicationCursor::default());
+        assert_eq!(sent.unwrap(), 1001);
+        assert_eq!(again.unwrap(), cursor);
+        assert_eq!(follower.root_hash(), leader.root_hash());
+
+        // later rounds only send what is new
+        let new = leader.put(b"new");
+        let (sent, cursor) = round(&leader, cursor);
+        let cursor = cursor.unwrap();
+        assert_eq!(sent.unwrap(), 1);
+        assert_eq!(&follower.get(new).unwrap()[..], b"new");
+        assert_eq!(round(&leader, cursor).0.unwrap(), 0);
+        assert_eq!(follower.root_hash(), leader.root_hash());
+
+        // a cursor means nothing to another leader
+        let other = Mapping::in_memory();
+        other.put(b"other");
+        let (served, pulled) = round(&other, cursor);
+        assert_eq!(served.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(pulled.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        assert!(follower.get(*blake3::hash(b"other").as_bytes()).is_none());
+    }
+}
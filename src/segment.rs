@@ -0,0 +1,103 @@
+use std::path::{Path, PathBuf};
+
+use crate::{
+    record::Codec,
+    storage::{FileStorage, MmapStorage, Storage},
+};
+
+/// Record pointers hold the segment number above this many bits, and the offset within the segment below them.
+pub const SEGMENT_SHIFT: u32 = 40;
+
+/// Combines a segment number and an offset within it into a record pointer.
+pub fn join(segment: u32, offset: u64) -> u64 {
+    ((segment as u64) << SEGMENT_SHIFT) | offset
+}
+
+/// Splits a record pointer into a segment number and an offset within it.
+pub fn split(ptr: u64) -> (u32, u64) {
+    (
+        (ptr >> SEGMENT_SHIFT) as u32,
+        ptr & ((1 << SEGMENT_SHIFT) - 1),
+    )
+}
+
+/// The file name of a segment within a segmented database's directory.
+pub fn segment_name(id: u32) -> String {
+    format!("{id:08x}.seg")
+}
+
+/// Parses the segment number out of a segment's file name.
+pub fn parse_segment_name(name: &str) -> Option<u32> {
+    let id = name.strip_suffix(".seg")?;
+    if id.len() != 8 {
+        return None;
+    }
+    u32::from_str_radix(id, 16).ok()
+}
+
+/// The directory of a segmented log.
+pub struct SegmentDir {
+    /// Where the segment files are
+    pub path: PathBuf,
+    /// The length past which a new segment is started
+    pub segment_size: u64,
+    /// Whether segments are read through an mmap, rather than with pread
+    pub mmap: bool,
+}
+
+impl SegmentDir {
+    /// Lists the numbers of the segments in the directory, in order, creating the directory if needed.
+    pub fn list(&self) -> std::io::Result<Vec<u32>> {
+        std::fs::create_dir_all(&self.path)?;
+        let mut ids = vec![];
+        for entry in std::fs::read_dir(&self.path)? {
+            if let Some(id) = entry?.file_name().to_str().and_then(parse_segment_name) {
+                ids.push(id);
+            }
+        }
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    /// Opens a segment, creating it if it does not exist.
+    pub fn open(&self, id: u32) -> std::io::Result<Box<dyn Storage>> {
+        open_file(&self.path.join(segment_name(id)), self.mmap)
+    }
+
+    /// Deletes a segment's file.
+    pub fn remove(&self, id: u32) -> std::io::Result<()> {
+        std::fs::remove_file(self.path.join(segment_name(id)))
+    }
+}
+
+/// Opens a file as storage, either through an mmap or with pread.
+pub fn open_file(fname: &Path, mmap: bool) -> std::io::Result<Box<dyn Storage>> {
+    Ok(if mmap {
+        Box::new(MmapStorage::open(fname)?)
+    } else {
+        Box::new(FileStorage::open(fname)?)
+    })
+}
+
+/// Generates a fresh random divider.
+pub(crate) fn random_divider() -> u128 {
+    let mut random_divider = [0u8; 16];
+    getrandom::fill(&mut random_divider).unwrap();
+    u128::from_le_bytes(random_divider)
+}
+
+/// Where the reserved region holds the value that checks the encryption key, or zeros if there is none.
+pub const KEY_CHECK_OFFSET: u64 = 26;
+
+/// Where the reserved region holds the value that checks the authentication secret, or zeros if there is none.
+pub const AUTH_CHECK_OFFSET: u64 = 58;
+
+/// The 4 KiB reserved region at the start of every file, with the given divider and the checks of the codec's keys.
+pub(crate) fn reserved_region(divider: u128, codec: &Codec) -> Vec<u8> {
+    let mut region = vec![0u8; 4096];
+    region[..10].copy_from_slice(b"meshanina2");
+    region[10..26].copy_from_slice(&divider.to_le_bytes());
+    region[26..58].copy_from_slice(&codec.key_check().unwrap_or_default());
+    region[58..90].copy_from_slice(&codec.auth_check().unwrap_or_default());
+    region
+}
@@ -0,0 +1,414 @@
+use std::{borrow::Cow, fs::File, path::Path, sync::Arc};
+
+use bytes::Bytes;
+use fs2::FileExt;
+use memmap::{MmapMut, MmapOptions};
+
+/// Where the bytes of one log file live. Records are only ever appended, except that erasing them overwrites or punches out bytes in place, and rolling back a failed write truncates.
+pub trait Storage: Send + Sync + 'static {
+    /// The length of the storage.
+    fn len(&self) -> u64;
+
+    /// Whether the storage is empty.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Reads exactly `len` bytes at the given offset, which must all be within the storage.
+    ///
+    /// Backends may borrow the bytes rather than copying them, but only if they stay valid, at the same address, for as long as the storage is alive, even across later appends: values handed out by [crate::Mapping::get] point straight into them.
+    fn read_at(&self, offset: u64, len: usize) -> std::io::Result<Cow<'_, [u8]>>;
+
+    /// Appends bytes to the end of the storage.
+    fn append(&mut self, data: &[u8]) -> std::io::Result<()>;
+
+    /// Overwrites bytes that were already appended.
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> std::io::Result<()>;
+
+    /// Truncates the storage to the given length.
+    fn truncate(&mut self, len: u64) -> std::io::Result<()>;
+
+    /// Grows the storage by `len` bytes that read back as zeros until they are overwritten. By default, zeros are appended.
+    fn extend(&mut self, len: u64) -> std::io::Result<()> {
+        let zeros = vec![0u8; 65536];
+        let mut remaining = len;
+        while remaining > 0 {
+            let n = remaining.min(zeros.len() as u64);
+            self.append(&zeros[..n as usize])?;
+            remaining -= n;
+        }
+        Ok(())
+    }
+
+    /// Makes everything written so far durable.
+    fn sync(&self) -> std::io::Result<()>;
+
+    /// Deallocates a byte range, which then reads back as zeros, without changing the length of the storage. Unsupported by default.
+    fn punch_hole(&mut self, _offset: u64, _len: u64) -> std::io::Result<()> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "this storage cannot punch holes",
+        ))
+    }
+
+    /// Converts a slice borrowed from [Storage::read_at] into [Bytes] without copying, if the backend can. Returns None otherwise, or if the slice did not come from this storage.
+    fn share(&self, _value: &[u8]) -> Option<Bytes> {
+        None
+    }
+}
+
+/// Mmaps are never shorter than this.
+const MIN_MAP_LEN: u64 = 1 << 20;
+
+/// A file, appended to with positional writes and read through an mmap, so that reads borrow rather than copy. This is the default.
+///
+/// The mmap starts out a bit longer than the file, and is replaced by one twice as long whenever the file outgrows it. Replaced mmaps are kept until the storage is dropped, since values borrowed from them may still be in use; as each is half as long as the next, this at most doubles the address space used.
+pub struct MmapStorage {
+    /// The file, which stays locked
+    file: File,
+    /// Mmap of the file, shared with any [Bytes] handed out
+    mmap: Arc<MmapMut>,
+    /// Mmaps that were replaced by a longer one
+    retired: Vec<Arc<MmapMut>>,
+    /// Length of the file
+    len: u64,
+}
+
+impl MmapStorage {
+    /// Opens a file, creating it if it does not exist, and locks it exclusively.
+    pub fn open(fname: impl AsRef<Path>) -> std::io::Result<Self> {
+        let (file, len) = open_locked(fname.as_ref())?;
+        let mmap = map(&file, len)?;
+        Ok(Self {
+            file,
+            mmap: Arc::new(mmap),
+            retired: vec![],
+            len,
+        })
+    }
+}
+
+impl Storage for MmapStorage {
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn read_at(&self, offset: u64, len: usize) -> std::io::Result<Cow<'_, [u8]>> {
+        check_bounds(offset, len, self.len)?;
+        Ok(Cow::Borrowed(
+            &self.mmap[offset as usize..offset as usize + len],
+        ))
+    }
+
+    fn append(&mut self, data: &[u8]) -> std::io::Result<()> {
+        write_all_at(&self.file, data, self.len)?;
+        self.len += data.len() as u64;
+        // replace the mmap with a longer one if the file has outgrown it
+        if self.len > self.mmap.len() as u64 {
+            let mmap = Arc::new(map(&self.file, self.len)?);
+            self.retired.push(std::mem::replace(&mut self.mmap, mmap));
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> std::io::Result<()> {
+        check_bounds(offset, data.len(), self.len)?;
+        write_all_at(&self.file, data, offset)
+    }
+
+    fn truncate(&mut self, len: u64) -> std::io::Result<()> {
+        self.file.set_len(len)?;
+        self.len = len;
+        Ok(())
+    }
+
+    fn extend(&mut self, len: u64) -> std::io::Result<()> {
+        self.file.set_len(self.len + len)?;
+        self.len += len;
+        if self.len > self.mmap.len() as u64 {
+            let mmap = Arc::new(map(&self.file, self.len)?);
+            self.retired.push(std::mem::replace(&mut self.mmap, mmap));
+        }
+        Ok(())
+    }
+
+    fn sync(&self) -> std::io::Result<()> {
+        self.file.sync_all()
+    }
+
+    fn punch_hole(&mut self, offset: u64, len: u64) -> std::io::Result<()> {
+        punch_hole(&self.file, offset, len)
+    }
+
+    fn share(&self, value: &[u8]) -> Option<Bytes> {
+        let mmap_range = self.mmap.as_ptr_range();
+        if !mmap_range.contains(&value.as_ptr()) {
+            return None;
+        }
+        let start = value.as_ptr() as usize - mmap_range.start as usize;
+        Some(Bytes::from_owner(MmapOwner(self.mmap.clone())).slice(start..start + value.len()))
+    }
+}
+
+/// A file, read and written with plain positional reads and writes (`pread` and `pwrite`). Every read copies, but no address space is used, so this works under tight memory limits.
+pub struct FileStorage {
+    /// The file, which stays locked
+    file: File,
+    /// Length of the file
+    len: u64,
+}
+
+impl FileStorage {
+    /// Opens a file, creating it if it does not exist, and locks it exclusively.
+    pub fn open(fname: impl AsRef<Path>) -> std::io::Result<Self> {
+        let (file, len) = open_locked(fname.as_ref())?;
+        Ok(Self { file, len })
+    }
+}
+
+impl Storage for FileStorage {
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn read_at(&self, offset: u64, len: usize) -> std::io::Result<Cow<'_, [u8]>> {
+        check_bounds(offset, len, self.len)?;
+        let mut buf = vec![0u8; len];
+        read_exact_at(&self.file, &mut buf, offset)?;
+        Ok(Cow::Owned(buf))
+    }
+
+    fn append(&mut self, data: &[u8]) -> std::io::Result<()> {
+        write_all_at(&self.file, data, self.len)?;
+        self.len += data.len() as u64;
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> std::io::Result<()> {
+        check_bounds(offset, data.len(), self.len)?;
+        write_all_at(&self.file, data, offset)
+    }
+
+    fn truncate(&mut self, len: u64) -> std::io::Result<()> {
+        self.file.set_len(len)?;
+        self.len = len;
+        Ok(())
+    }
+
+    fn extend(&mut self, len: u64) -> std::io::Result<()> {
+        self.file.set_len(self.len + len)?;
+        self.len += len;
+        Ok(())
+    }
+
+    fn sync(&self) -> std::io::Result<()> {
+        self.file.sync_all()
+    }
+
+    fn punch_hole(&mut self, offset: u64, len: u64) -> std::io::Result<()> {
+        punch_hole(&self.file, offset, len)
+    }
+}
+
+/// A buffer in memory, for ephemeral databases and tests. Nothing is ever persisted. Every read copies, since appending may move the buffer.
+#[derive(Default)]
+pub struct MemoryStorage {
+    data: Vec<u8>,
+}
+
+impl MemoryStorage {
+    /// Creates an empty in-memory storage.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn len(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    fn read_at(&self, offset: u64, len: usize) -> std::io::Result<Cow<'_, [u8]>> {
+        check_bounds(offset, len, self.len())?;
+        Ok(Cow::Owned(
+            self.data[offset as usize..offset as usize + len].to_vec(),
+        ))
+    }
+
+    fn append(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.data.extend_from_slice(data);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> std::io::Result<()> {
+        check_bounds(offset, data.len(), self.len())?;
+        self.data[offset as usize..offset as usize + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: u64) -> std::io::Result<()> {
+        self.data.truncate(len as usize);
+        Ok(())
+    }
+
+    fn extend(&mut self, len: u64) -> std::io::Result<()> {
+        self.data.resize(self.data.len() + len as usize, 0);
+        Ok(())
+    }
+
+    fn sync(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn punch_hole(&mut self, offset: u64, len: u64) -> std::io::Result<()> {
+        check_bounds(offset, len as usize, self.len())?;
+        self.data[offset as usize..(offset + len) as usize].fill(0);
+        Ok(())
+    }
+}
+
+/// Fails if a byte range is not within a storage of the given length.
+fn check_bounds(offset: u64, len: usize, storage_len: u64) -> std::io::Result<()> {
+    if offset.saturating_add(len as u64) > storage_len {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "range past the end of the storage",
+        ));
+    }
+    Ok(())
+}
+
+/// Opens a file for reading and writing, creating it if needed, and locks it exclusively. Returns the file and its length.
+fn open_locked(fname: &Path) -> std::io::Result<(File, u64)> {
+    let handle = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(fname)?;
+    handle.try_lock_exclusive()?;
+    let len = handle.metadata()?.len();
+    Ok((handle, len))
+}
+
+/// Mmaps a file of the given length, leaving room for it to grow to the next power of two.
+fn map(file: &File, len: u64) -> std::io::Result<MmapMut> {
+    let map_len = len.next_power_of_two().max(MIN_MAP_LEN);
+    let mut mmap = unsafe { MmapOptions::new().len(map_len as usize).map_mut(file)? };
+    // when possible (on linux), advise the OS that we're gonna read from the mmap pretty randomly, so tricks like readahead aren't gonna help at all
+    #[cfg(target_os = "linux")]
+    unsafe {
+        use libc::MADV_RANDOM;
+        libc::madvise(&mut mmap[0] as *mut u8 as _, mmap.len(), MADV_RANDOM);
+    }
+    Ok(mmap)
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &File, data: &[u8], offset: u64) -> std::io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, data, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> std::io::Result<()> {
+    while !buf.is_empty() {
+        match std::os::windows::fs::FileExt::seek_read(file, buf, offset)? {
+            0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn write_all_at(file: &File, mut data: &[u8], mut offset: u64) -> std::io::Result<()> {
+    while !data.is_empty() {
+        match std::os::windows::fs::FileExt::seek_write(file, data, offset)? {
+            0 => return Err(std::io::ErrorKind::WriteZero.into()),
+            n => {
+                data = &data[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Deallocates a byte range of a file, which then reads back as zeros, without changing the file's length.
+#[cfg(target_os = "linux")]
+fn punch_hole(file: &File, offset: u64, len: u64) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn punch_hole(_file: &File, _offset: u64, _len: u64) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "hole punching is only supported on Linux",
+    ))
+}
+
+/// A shared mmap, as an owner of [Bytes].
+struct MmapOwner(Arc<MmapMut>);
+
+impl AsRef<[u8]> for MmapOwner {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backends_agree() {
+        let dir = tempfile::tempdir().unwrap();
+        let backends: Vec<Box<dyn Storage>> = vec![
+            Box::new(MmapStorage::open(dir.path().join("mmap")).unwrap()),
+            Box::new(FileStorage::open(dir.path().join("pread")).unwrap()),
+            Box::new(MemoryStorage::new()),
+        ];
+        for mut storage in backends {
+            assert!(storage.is_empty());
+            storage.append(b"hello").unwrap();
+            storage.append(&vec![7u8; 3 << 20]).unwrap();
+            assert_eq!(storage.len(), 5 + (3 << 20));
+            assert_eq!(&storage.read_at(0, 5).unwrap()[..], b"hello");
+            storage.write_at(1, b"ipp").unwrap();
+            assert_eq!(&storage.read_at(0, 6).unwrap()[..], b"hippo\x07");
+            assert!(storage.read_at(storage.len() - 1, 2).is_err());
+            assert!(storage.write_at(storage.len(), b"x").is_err());
+            storage.truncate(3).unwrap();
+            assert_eq!(storage.len(), 3);
+            storage.append(b"s").unwrap();
+            assert_eq!(&storage.read_at(0, 4).unwrap()[..], b"hips");
+            storage.extend(2 << 20).unwrap();
+            assert_eq!(storage.len(), 4 + (2 << 20));
+            assert_eq!(&storage.read_at(3, 3).unwrap()[..], b"s\0\0");
+            storage.write_at(storage.len() - 1, b"!").unwrap();
+            assert_eq!(&storage.read_at(storage.len() - 2, 2).unwrap()[..], b"\0!");
+            storage.sync().unwrap();
+        }
+    }
+}
@@ -1,19 +1,34 @@
 use std::{
     borrow::Cow,
-    io::{Seek, SeekFrom, Write},
-    path::Path,
-    sync::Arc,
+    io::Write,
+    sync::{Arc, Weak, mpsc::Sender},
 };
 
+use anyhow::Context;
 use arrayref::array_ref;
-use fs2::FileExt;
+use bytes::Bytes;
 use itertools::Itertools;
-use memmap::{MmapMut, MmapOptions};
+use parking_lot::Mutex;
+use rustc_hash::FxHashMap;
 
-use crate::record::{Record, RecordPtr};
+use crate::{
+    merkle::{Proof, ProofNode, leaf_hash, node_hash},
+    record::{Codec, DataStream, Encoding, MAX_PREFIX_LEN, Record, RecordPtr, RootAuth},
+    segment::{
+        AUTH_CHECK_OFFSET, KEY_CHECK_OFFSET, SEGMENT_SHIFT, SegmentDir, join, random_divider,
+        reserved_region, split,
+    },
+    storage::Storage,
+};
 
 pub const MAX_FLUSH_INTERVAL: u64 = 10 * 1024 * 1024;
 
+/// How many Merkle hashes of HAMT nodes are remembered at most, which takes up some tens of megabytes.
+const MAX_CACHED_HASHES: usize = 1 << 20;
+
+/// A wait for a key, registered by [Table::watch]: a handle that tells whether anyone still waits, and the channel to signal.
+type Wait = (Weak<()>, Sender<()>);
+
 /// Low-level interface to the database.
 pub struct Table {
     /// Root record. Must be a HAMT!
@@ -22,157 +37,1223 @@ pub struct Table {
     dirty: bool,
     /// The secret divider
     divider: u128,
-    /// Mmap of the file
-    mmap: MmapMut,
-    /// Append-writer
-    writer: std::fs::File,
-    /// Pointer
-    ptr: u64,
+    /// How data records are written
+    encoding: Encoding,
+    /// The encoding, bound to the divider
+    codec: Arc<Codec>,
+    /// Segments of the log, indexed by segment number. The last one is appended to; dropped ones leave a gap.
+    segments: Vec<Option<Box<dyn Storage>>>,
+    /// For a segmented log, the directory holding the segments
+    dir: Option<SegmentDir>,
+    /// Dropped segments, which values borrowed earlier may still point into
+    graveyard: Vec<Box<dyn Storage>>,
     /// Last flush position
     last_flush_ptr: u64,
+    /// Pointer to the root record on disk, if it has ever been flushed
+    root_ptr: Option<u64>,
+    /// The latest authenticated root and its MAC, which the next one is chained to
+    last_auth: Option<(u64, [u8; 32])>,
+    /// Merkle hashes of HAMT nodes on disk, which never change once written, up to [MAX_CACHED_HASHES] of them
+    node_hashes: Mutex<FxHashMap<u64, [u8; 32]>>,
+    /// Waits for particular keys to be inserted
+    watchers: FxHashMap<[u8; 32], Vec<Wait>>,
+    /// Channels that every newly inserted key is sent to
+    subscribers: Vec<Sender<[u8; 32]>>,
+    /// Pointers to and lengths of the rooms reserved for records that are still being streamed in
+    streaming: Vec<(u64, u64)>,
+    /// Roots that open transactions started from, whose records must outlive the current root
+    snapshots: Vec<Weak<Record<'static>>>,
+}
+
+/// A value found by [Table::lookup_at]: either already loaded, or in a plain data record on disk, any part of which can be read on its own.
+pub enum ValueAt<'a, 's> {
+    /// A value in memory, or in a data record that had to be decoded whole
+    Loaded(&'a Cow<'s, [u8]>),
+    /// A value stored as is, given by its segment, its offset within it and its length
+    OnDisk(&'s dyn Storage, u64, u64),
+}
+
+impl<'s> ValueAt<'_, 's> {
+    /// The length of the value.
+    pub fn len(&self) -> u64 {
+        match self {
+            ValueAt::Loaded(v) => v.len() as u64,
+            ValueAt::OnDisk(_, _, len) => *len,
+        }
+    }
+
+    /// Reads part of the value. The range is clamped to the value.
+    pub fn read(&self, start: u64, end: u64) -> std::io::Result<Cow<'s, [u8]>> {
+        let start = start.min(self.len());
+        let end = end.clamp(start, self.len());
+        match self {
+            ValueAt::Loaded(Cow::Borrowed(v)) => {
+                Ok(Cow::Borrowed(&v[start as usize..end as usize]))
+            }
+            ValueAt::Loaded(Cow::Owned(v)) => {
+                Ok(Cow::Owned(v[start as usize..end as usize].to_vec()))
+            }
+            ValueAt::OnDisk(segment, offset, _) => {
+                segment.read_at(offset + start, (end - start) as usize)
+            }
+        }
+    }
+}
+
+/// Room at the end of the log for a large data record that is being streamed in. See [Table::reserve_stream].
+pub struct StreamReservation {
+    ptr: u64,
+    key: [u8; 32],
+    stream: DataStream,
 }
 
 impl Table {
-    /// Opens a new file, doing recovery as needed.
-    pub fn open(fname: &Path) -> std::io::Result<Self> {
-        let mut handle = std::fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .truncate(false)
-            .open(fname)?;
-        handle.try_lock_exclusive()?;
-        // ensure the existence of the reserved region
-        if handle.seek(SeekFrom::End(0))? < 4096 {
-            handle.set_len(4096)?;
-            handle.seek(SeekFrom::Start(0))?;
-            handle.write_all(b"meshanina2")?;
-            let mut random_divider = [0u8; 16];
-            getrandom::fill(&mut random_divider).unwrap();
-            handle.write_all(&random_divider)?;
-        }
-        // mmap everything
-        let mut mmap = unsafe { MmapOptions::new().len(1 << 39).map_mut(&handle).unwrap() };
-        // when possible (on linux), advise the OS that we're gonna read from the mmap pretty randomly, so tricks like readahead aren't gonna help at all
-        #[cfg(target_os = "linux")]
-        unsafe {
-            use libc::MADV_RANDOM;
-            libc::madvise(&mut mmap[0] as *mut u8 as _, mmap.len(), MADV_RANDOM);
-        }
-        let divider = u128::from_le_bytes(*array_ref![&mmap, 10, 16]);
-        handle.seek(SeekFrom::Start(0))?;
-        let file_len = handle.seek(SeekFrom::End(0))?;
-        // if the file is long, we attempt to find the last valid HAMT root node.
-        if file_len > 4096 {
-            log::debug!("file length {file_len}, finding last HAMT node");
-
-            let search_space = &mmap[4096..file_len as usize];
-            let search_space = &search_space
-                [search_space.len() - (MAX_FLUSH_INTERVAL as usize * 2).min(search_space.len())..];
-            let posn_in_space = search_space
-                .windows(16)
-                .positions(|window| window == divider.to_le_bytes())
-                .collect_vec();
-            if posn_in_space.is_empty() {
-                panic!("db corruption: no dividers found in the last part of db")
-            }
-            for posn in posn_in_space.into_iter().rev() {
-                if let Ok(rec) = Record::new_borrowed(&search_space[posn..], divider) {
-                    if rec.is_root() {
-                        let ptr = handle.stream_position()?;
-                        return Ok(Table {
-                            root: rec.into_owned(),
-                            dirty: false,
-                            divider,
-                            mmap,
-                            writer: handle,
-                            ptr,
-                            last_flush_ptr: ptr,
-                        });
-                    }
+    /// Opens a single-segment table on the given storage, doing recovery as needed.
+    pub fn new(storage: Box<dyn Storage>) -> std::io::Result<Self> {
+        Self::recover(vec![Some(storage)], None)
+    }
+
+    /// Opens a segmented log in the given directory, doing recovery as needed.
+    pub fn open_dir(dir: SegmentDir) -> std::io::Result<Self> {
+        let ids = dir.list()?;
+        let mut segments = vec![];
+        for id in ids.iter().copied().chain(ids.is_empty().then_some(0)) {
+            segments.resize_with(id as usize, || None);
+            segments.push(Some(dir.open(id)?));
+        }
+        Self::recover(segments, Some(dir))
+    }
+
+    /// Finds the last valid HAMT root in the given segments.
+    fn recover(
+        mut segments: Vec<Option<Box<dyn Storage>>>,
+        dir: Option<SegmentDir>,
+    ) -> std::io::Result<Self> {
+        // ensure the existence of the reserved region, with the same divider everywhere
+        let mut divider = None;
+        for segment in segments.iter_mut().flatten() {
+            if segment.len() >= 4096 {
+                let region = segment.read_at(0, 26)?;
+                let found = u128::from_le_bytes(*array_ref![region, 10, 16]);
+                if *divider.get_or_insert(found) != found {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "segments belong to different databases",
+                    ));
                 }
             }
-            panic!("db corruption: dividers found but none of the elements were valid roots")
         }
-        let ptr = handle.stream_position()?;
-        Ok(Table {
+        let divider = divider.unwrap_or_else(random_divider);
+        for segment in segments.iter_mut().flatten() {
+            init_segment(segment.as_mut(), divider, &Codec::default())?;
+        }
+        let mut table = Table {
             root: Record::HamtNode(true, 0, vec![]),
             dirty: false,
             divider,
-            mmap,
-            writer: handle,
-            ptr,
-            last_flush_ptr: ptr,
-        })
+            encoding: Default::default(),
+            codec: Default::default(),
+            segments,
+            dir,
+            graveyard: vec![],
+            last_flush_ptr: 0,
+            root_ptr: None,
+            last_auth: None,
+            node_hashes: Default::default(),
+            watchers: Default::default(),
+            subscribers: vec![],
+            streaming: vec![],
+            snapshots: vec![],
+        };
+        table.last_flush_ptr = table.ptr();
+        // we search the segments from the last one backwards, since a crash right after starting a new segment can leave it without a root
+        let mut nonempty = false;
+        for (id, segment) in table.segments.iter().enumerate().rev() {
+            let Some(segment) = segment else { continue };
+            let file_len = segment.len();
+            // if the file is long, we attempt to find the last valid HAMT node.
+            if file_len <= 4096 {
+                continue;
+            }
+            nonempty = true;
+            log::debug!("segment {id} length {file_len}, finding last HAMT node");
+
+            // roots are usually near the end, but a large record that was being streamed in when we crashed can be in the way, so we keep going back window by window. Windows overlap by more than the longest root, so that every root fits whole in one of them.
+            let mut end = file_len;
+            while end > 4096 {
+                let start = end.saturating_sub(MAX_FLUSH_INTERVAL * 2).max(4096);
+                let search_space = segment.read_at(start, (end - start) as usize)?;
+                let posn_in_space = search_space
+                    .windows(16)
+                    .positions(|window| window == divider.to_le_bytes())
+                    .collect_vec();
+                for posn in posn_in_space.into_iter().rev() {
+                    if let Ok(rec) = Record::new_borrowed(&search_space[posn..], divider)
+                        && rec.is_root()
+                    {
+                        let root_ptr = start + posn as u64;
+                        table.root = rec.into_owned();
+                        table.root_ptr = Some(join(id as u32, root_ptr));
+                        return Ok(table);
+                    }
+                }
+                if start == 4096 {
+                    break;
+                }
+                end = start + 4096;
+            }
+        }
+        if nonempty {
+            panic!("db corruption: no valid roots found in db")
+        }
+        Ok(table)
     }
 
     /// Looks up a key, returning the value if possible.
     pub fn lookup(&self, key: [u8; 32]) -> Option<Cow<'_, [u8]>> {
-        let mut ptr = self.root.clone();
+        self.lookup_in(&self.root, key)
+    }
+
+    /// Looks up a key starting from an arbitrary root, rather than the current one.
+    pub fn lookup_in<'s>(&'s self, root: &Record<'s>, key: [u8; 32]) -> Option<Cow<'s, [u8]>> {
+        self.lookup_with(root, key, |v| v.clone())
+    }
+
+    /// Looks up a key starting from an arbitrary root, passing the value to a closure. Nothing is copied along the way, so the closure can look at just part of a large value.
+    pub fn lookup_with<'s, R>(
+        &'s self,
+        root: &Record<'s>,
+        key: [u8; 32],
+        f: impl FnOnce(&Cow<'s, [u8]>) -> R,
+    ) -> Option<R> {
         // TODO use all the bits
+        let ikey = u128::from_le_bytes(*array_ref![&key, 0, 16]);
+        self.lookup_helper(root, ikey, key, f)
+    }
+
+    fn lookup_helper<'s, R>(
+        &'s self,
+        node: &Record<'s>,
+        ikey: u128,
+        key: [u8; 32],
+        f: impl FnOnce(&Cow<'s, [u8]>) -> R,
+    ) -> Option<R> {
+        match node {
+            Record::Data(d_key, d_v) => {
+                if key != *d_key {
+                    None
+                } else {
+                    Some(f(d_v))
+                }
+            }
+            Record::HamtNode(_, bitmap, ptrs) => {
+                let hindex = (ikey & 0b111111) as u32;
+                if (bitmap >> hindex) & 1 == 1 {
+                    let idx = (bitmap & ((1 << hindex) - 1)).count_ones();
+                    match &ptrs[idx as usize] {
+                        RecordPtr::InMemory(r) => self.lookup_helper(r, ikey >> 6, key, f),
+                        RecordPtr::OnDisk(p) => {
+                            self.lookup_helper(&self.load_record(*p), ikey >> 6, key, f)
+                        }
+                    }
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// Looks up a key below the current root, passing its value to a closure as a [ValueAt]. Unlike [Table::lookup_with], a value in a plain data record on disk is not read at all, so the closure can read just the part it needs.
+    pub fn lookup_at<'s, R>(
+        &'s self,
+        key: [u8; 32],
+        f: impl FnOnce(ValueAt<'_, 's>) -> R,
+    ) -> Option<R> {
+        let ikey = u128::from_le_bytes(*array_ref![&key, 0, 16]);
+        self.lookup_at_helper(&self.root, ikey, key, f)
+    }
+
+    fn lookup_at_helper<'s, R>(
+        &'s self,
+        node: &Record<'s>,
+        ikey: u128,
+        key: [u8; 32],
+        f: impl FnOnce(ValueAt<'_, 's>) -> R,
+    ) -> Option<R> {
+        match node {
+            Record::Data(d_key, d_v) => (key == *d_key).then(|| f(ValueAt::Loaded(d_v))),
+            Record::HamtNode(_, bitmap, ptrs) => {
+                let hindex = (ikey & 0b111111) as u32;
+                if (bitmap >> hindex) & 1 == 0 {
+                    return None;
+                }
+                let idx = (bitmap & ((1 << hindex) - 1)).count_ones();
+                match &ptrs[idx as usize] {
+                    RecordPtr::InMemory(r) => self.lookup_at_helper(r, ikey >> 6, key, f),
+                    RecordPtr::OnDisk(p) => {
+                        let (segment, offset) = split(*p);
+                        let segment = self
+                            .segments
+                            .get(segment as usize)
+                            .and_then(|s| s.as_deref())
+                            .expect("db corruption: dangling ptr");
+                        match peek_record(segment, offset, self.divider, &self.codec)
+                            .expect("db corruption: dangling ptr")
+                        {
+                            Peeked::Plain(d_key, start, len) => {
+                                (key == d_key).then(|| f(ValueAt::OnDisk(segment, start, len)))
+                            }
+                            Peeked::Record(record) => {
+                                self.lookup_at_helper(&record, ikey >> 6, key, f)
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Calls a closure on every key-value pair below a root, in HAMT order, along with the offset of its data record if it is on disk. Fails if a record below the root cannot be read.
+    pub fn walk_leaves<'s>(
+        &'s self,
+        root: &Record<'s>,
+        f: &mut impl FnMut([u8; 32], &Cow<'s, [u8]>, Option<u64>),
+    ) -> anyhow::Result<()> {
+        if let Record::HamtNode(_, _, ptrs) = root {
+            for ptr in ptrs {
+                let (child, offset) = match ptr {
+                    RecordPtr::InMemory(r) => (Cow::Borrowed(&**r), None),
+                    RecordPtr::OnDisk(p) => (Cow::Owned(self.read_record(*p)?.0), Some(*p)),
+                };
+                match child.as_ref() {
+                    Record::Data(k, v) => f(*k, v, offset),
+                    node => self.walk_leaves(node, f)?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Computes the Merkle hash of the current root, including any unflushed changes. Since the shape of the HAMT only depends on the keys in it, this is a deterministic commitment to the set of keys.
+    pub fn root_hash(&self) -> [u8; 32] {
+        self.merkle_hash(&self.root)
+    }
+
+    /// Computes the Merkle hash of a data record or HAMT node.
+    pub fn merkle_hash(&self, record: &Record) -> [u8; 32] {
+        self.try_merkle_hash(record)
+            .expect("db corruption: dangling ptr")
+    }
+
+    /// Like [Table::merkle_hash], but returns an error instead of panicking on a record that cannot be read.
+    fn try_merkle_hash(&self, record: &Record) -> anyhow::Result<[u8; 32]> {
+        Ok(match record {
+            Record::Data(key, _) => leaf_hash(key),
+            Record::HamtNode(_, bitmap, ptrs) => node_hash(
+                *bitmap,
+                ptrs.iter()
+                    .map(|ptr| self.try_ptr_hash(ptr))
+                    .collect::<anyhow::Result<Vec<_>>>()?,
+            ),
+        })
+    }
+
+    /// Builds a proof that a key is, or is not, below the current root. It checks out against [Table::root_hash].
+    pub fn prove(&self, key: [u8; 32]) -> Proof {
         let mut ikey = u128::from_le_bytes(*array_ref![&key, 0, 16]);
-        loop {
-            match ptr {
-                Record::Data(d_key, d_v) => {
-                    if key != d_key {
-                        return None;
-                    } else {
-                        return Some(d_v.clone());
+        let mut proof = Proof {
+            path: vec![],
+            leaf: None,
+        };
+        let mut node = Cow::Borrowed(&self.root);
+        while let Record::HamtNode(_, bitmap, ptrs) = node.as_ref() {
+            let hindex = (ikey & 0b111111) as u32;
+            let idx = (bitmap & ((1 << hindex) - 1)).count_ones() as usize;
+            let on_path = ((bitmap >> hindex) & 1 == 1).then_some(idx);
+            proof.path.push(ProofNode {
+                bitmap: *bitmap,
+                siblings: ptrs
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, _)| Some(*i) != on_path)
+                    .map(|(_, ptr)| self.ptr_hash(ptr))
+                    .collect(),
+            });
+            let Some(idx) = on_path else {
+                break;
+            };
+            node = match &ptrs[idx] {
+                RecordPtr::InMemory(r) => Cow::Owned((**r).clone()),
+                RecordPtr::OnDisk(p) => match self.skim(*p).expect("db corruption: dangling ptr").0
+                {
+                    Skimmed::Node(node) => Cow::Owned(node),
+                    Skimmed::Data(key) => {
+                        proof.leaf = Some(key);
+                        break;
+                    }
+                },
+            };
+            if let Record::Data(k, _) = node.as_ref() {
+                proof.leaf = Some(*k);
+            }
+            ikey >>= 6;
+        }
+        proof
+    }
+
+    /// Computes the Merkle hash of the record a pointer points to.
+    fn ptr_hash(&self, ptr: &RecordPtr) -> [u8; 32] {
+        self.try_ptr_hash(ptr).expect("db corruption: dangling ptr")
+    }
+
+    /// Computes the Merkle hash of the record a pointer points to, remembering the hashes of nodes on disk. Only the key of a data record is read.
+    fn try_ptr_hash(&self, ptr: &RecordPtr) -> anyhow::Result<[u8; 32]> {
+        match ptr {
+            RecordPtr::InMemory(r) => self.try_merkle_hash(r),
+            RecordPtr::OnDisk(p) => {
+                if let Some(hash) = self.node_hashes.lock().get(p) {
+                    return Ok(*hash);
+                }
+                match self.skim(*p)?.0 {
+                    Skimmed::Data(key) => Ok(leaf_hash(&key)),
+                    Skimmed::Node(node) => {
+                        let hash = self.try_merkle_hash(&node)?;
+                        let mut node_hashes = self.node_hashes.lock();
+                        // the cache is only there to make repeated hashing cheap, so it is simply started over when full
+                        if node_hashes.len() >= MAX_CACHED_HASHES {
+                            node_hashes.clear();
+                        }
+                        node_hashes.insert(*p, hash);
+                        Ok(hash)
                     }
                 }
-                Record::HamtNode(_, bitmap, ptrs) => {
-                    let hindex = (ikey & 0b111111) as u32;
-                    if (bitmap >> hindex) & 1 == 1 {
-                        let idx = (bitmap & ((1 << hindex) - 1)).count_ones();
-                        let p = ptrs[idx as usize].clone();
-                        ptr = p.load(|p| self.load_record(p));
-                        ikey >>= 6;
-                    } else {
-                        return None;
+            }
+        }
+    }
+
+    /// Walks two HAMTs, which may belong to different tables, calling `only_a` or `only_b` with every key that is below just one of them. Subtrees that are the same on both sides are skipped without being walked: by pointer if both are in this table, and by Merkle hash otherwise. Fails if a record on either side cannot be read, such as one that was purged or in a dropped segment.
+    pub fn diff<'s, 'o>(
+        &'s self,
+        a: &Record<'s>,
+        other: &'o Table,
+        b: &Record<'o>,
+        only_a: &mut impl FnMut([u8; 32]),
+        only_b: &mut impl FnMut([u8; 32]),
+    ) -> anyhow::Result<()> {
+        let (Record::HamtNode(_, bitmap_a, ptrs_a), Record::HamtNode(_, bitmap_b, ptrs_b)) = (a, b)
+        else {
+            panic!("can only diff HAMT nodes")
+        };
+        let same_table = std::ptr::eq(self, other);
+        for hindex in 0..64 {
+            match (
+                slot(*bitmap_a, ptrs_a, hindex),
+                slot(*bitmap_b, ptrs_b, hindex),
+            ) {
+                (None, None) => {}
+                (Some(pa), None) => self.walk_keys(pa, only_a)?,
+                (None, Some(pb)) => other.walk_keys(pb, only_b)?,
+                (Some(pa), Some(pb)) => {
+                    let identical = match (pa, pb) {
+                        (RecordPtr::OnDisk(x), RecordPtr::OnDisk(y)) if same_table => x == y,
+                        _ => !same_table && self.try_ptr_hash(pa)? == other.try_ptr_hash(pb)?,
+                    };
+                    if identical {
+                        continue;
+                    }
+                    let ca = self.try_load(pa)?;
+                    let cb = other.try_load(pb)?;
+                    match (&ca, &cb) {
+                        (Record::Data(ka, _), Record::Data(kb, _)) => {
+                            if ka != kb {
+                                only_a(*ka);
+                                only_b(*kb);
+                            }
+                        }
+                        (Record::Data(ka, _), node) => {
+                            let mut found = false;
+                            other.walk_leaves(node, &mut |k, _, _| {
+                                if k == *ka { found = true } else { only_b(k) }
+                            })?;
+                            if !found {
+                                only_a(*ka);
+                            }
+                        }
+                        (node, Record::Data(kb, _)) => {
+                            let mut found = false;
+                            self.walk_leaves(node, &mut |k, _, _| {
+                                if k == *kb { found = true } else { only_a(k) }
+                            })?;
+                            if !found {
+                                only_b(*kb);
+                            }
+                        }
+                        _ => self.diff(&ca, other, &cb, only_a, only_b)?,
                     }
                 }
             }
         }
+        Ok(())
+    }
+
+    /// Calls a closure on every key below a pointer, which may point straight to a data record.
+    fn walk_keys(&self, ptr: &RecordPtr, f: &mut impl FnMut([u8; 32])) -> anyhow::Result<()> {
+        match self.try_load(ptr)? {
+            Record::Data(k, _) => f(k),
+            node => self.walk_leaves(&node, &mut |k, _, _| f(k))?,
+        }
+        Ok(())
+    }
+
+    /// Loads the record a pointer points to, failing instead of panicking if it cannot be read.
+    fn try_load<'s>(&'s self, ptr: &RecordPtr<'s>) -> anyhow::Result<Record<'s>> {
+        Ok(match ptr {
+            RecordPtr::InMemory(r) => (**r).clone(),
+            RecordPtr::OnDisk(p) => self.read_record(*p)?.0,
+        })
+    }
+
+    /// Loads a root flushed earlier, given its pointer.
+    pub fn load_root(&self, ptr: u64) -> std::io::Result<Record<'_>> {
+        match self.read_record(ptr) {
+            Ok((record, _)) if record.is_root() => Ok(record),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "no root at the given pointer",
+            )),
+        }
+    }
+
+    /// Pointer to the last root flushed to disk, if any.
+    pub fn root_ptr(&self) -> Option<u64> {
+        self.root_ptr
+    }
+
+    /// Converts a value returned by a lookup into [Bytes]. Values that live in the mmap are not copied; the [Bytes] instead keeps the mmap alive.
+    pub fn to_bytes(&self, value: &[u8]) -> Bytes {
+        self.segments
+            .iter()
+            .flatten()
+            .find_map(|segment| segment.share(value))
+            .unwrap_or_else(|| Bytes::copy_from_slice(value))
+    }
+
+    /// Sets how records are written from now on. Fails if the database has been opened with an encryption key or authentication secret before, and this encoding has a different one or none at all. Otherwise, a key or secret is remembered, so that it will be required from now on.
+    ///
+    /// With an authentication secret, the history of authenticated roots is verified, failing if it has been tampered with. A latest root that is not authenticated is only accepted if `adopt` is set, and the database was never authenticated before.
+    pub fn set_encoding(&mut self, encoding: Encoding, adopt: bool) -> std::io::Result<()> {
+        let codec = Codec::new(&encoding, self.divider);
+        let mut authenticated = false;
+        // checks are only written once everything is verified, so that a failed open changes nothing
+        let mut missing = vec![];
+        for (id, segment) in self.segments.iter().enumerate() {
+            let Some(segment) = segment else { continue };
+            for (offset, check, what) in [
+                (KEY_CHECK_OFFSET, codec.key_check(), "encryption key"),
+                (
+                    AUTH_CHECK_OFFSET,
+                    codec.auth_check(),
+                    "authentication secret",
+                ),
+            ] {
+                let stored = segment.read_at(offset, 32)?;
+                let stored = *array_ref![stored, 0, 32];
+                if offset == AUTH_CHECK_OFFSET && stored != [0; 32] {
+                    authenticated = true;
+                }
+                match check {
+                    Some(check) if stored == [0; 32] => missing.push((id, offset, check)),
+                    Some(check) if stored != check => {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::PermissionDenied,
+                            format!("wrong {what}"),
+                        ));
+                    }
+                    None if stored != [0; 32] => {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::PermissionDenied,
+                            format!("database needs an {what}, but none was given"),
+                        ));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        if let Some(root_key) = codec.root_key() {
+            self.last_auth = self
+                .verify_history(root_key, adopt && !authenticated)
+                .map_err(|e| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("db tampering: {e:#}"),
+                    )
+                })?;
+        }
+        for (id, offset, check) in missing {
+            let segment = self.segments[id].as_mut().unwrap();
+            segment.write_at(offset, &check)?;
+            segment.sync()?;
+        }
+        self.encoding = encoding;
+        self.codec = Arc::new(codec);
+        Ok(())
+    }
+
+    /// Walks back through the chain of authenticated roots from the current root, checking every MAC and every link, and the Merkle hash of the tree below the current root. Returns the current root and its MAC, if it is authenticated. The walk stops early where older history has been released, by punching garbage or dropping segments.
+    ///
+    /// If `adopt` is set, the current root may be one that is not authenticated, since authentication is just being turned on. Otherwise, that is taken as a forgery, whatever the reserved region says: it is not authenticated itself.
+    fn verify_history(
+        &self,
+        root_key: &[u8; 32],
+        adopt: bool,
+    ) -> anyhow::Result<Option<(u64, [u8; 32])>> {
+        let Some(root_ptr) = self.root_ptr else {
+            return Ok(None);
+        };
+        let latest = match self.root_auth(root_ptr, root_key)? {
+            Some(auth) => {
+                // the MAC only reaches the tree below the root through its Merkle hash, so that has to be recomputed from what is on disk
+                let (root, _) = self.read_record(root_ptr)?;
+                if self.try_merkle_hash(&root)? != auth.merkle {
+                    anyhow::bail!("tree below the latest root does not match its hash");
+                }
+                (root_ptr, auth.mac)
+            }
+            None if adopt => return Ok(None),
+            None => anyhow::bail!("latest root is not authenticated"),
+        };
+        let mut expected = latest.1;
+        let mut prev = Some(root_ptr);
+        while let Some(ptr) = prev {
+            let (segment, offset) = split(ptr);
+            let Some(segment) = self
+                .segments
+                .get(segment as usize)
+                .and_then(|s| s.as_deref())
+            else {
+                break;
+            };
+            if !segment
+                .read_at(offset, 16)
+                .is_ok_and(|b| b[..] == self.divider.to_le_bytes())
+            {
+                break;
+            }
+            let auth = self
+                .root_auth(ptr, root_key)?
+                .context("authenticated root chained to one that is not")?;
+            if auth.mac != expected {
+                anyhow::bail!("broken chain of roots at {ptr:#x}");
+            }
+            expected = auth.prev_mac;
+            prev = auth.prev;
+        }
+        Ok(Some(latest))
+    }
+
+    /// Reads the authentication trailer of the root at the given pointer, checking its MAC.
+    fn root_auth(&self, ptr: u64, root_key: &[u8; 32]) -> anyhow::Result<Option<RootAuth>> {
+        let (segment, offset) = split(ptr);
+        let segment = self
+            .segments
+            .get(segment as usize)
+            .and_then(|s| s.as_deref())
+            .context("pointer into a dropped segment")?;
+        let bytes = read_raw(segment, offset, self.divider)?;
+        Record::root_auth(&bytes, self.divider, root_key)
+            .with_context(|| format!("bad root at {ptr:#x}"))
+    }
+
+    /// An identifier of the database, derived from its divider, which stays the same for as long as the log does. It gives nothing away about the divider.
+    pub fn database_id(&self) -> [u8; 32] {
+        blake3::derive_key("meshanina 2024-06 database id", &self.divider.to_le_bytes())
+    }
+
+    /// How data records are written.
+    pub fn encoding(&self) -> &Encoding {
+        &self.encoding
+    }
+
+    /// Returns the current root, including any unflushed changes.
+    pub fn root(&self) -> &Record<'static> {
+        &self.root
+    }
+
+    /// Returns the current root as a snapshot, whose records are kept by [Table::punch_garbage] for as long as it is alive.
+    pub fn snapshot(&mut self) -> Arc<Record<'static>> {
+        let snapshot = Arc::new(self.root.clone());
+        self.snapshots.retain(|s| s.strong_count() > 0);
+        self.snapshots.push(Arc::downgrade(&snapshot));
+        snapshot
     }
 
     /// Looks up a single record.
     fn load_record(&self, ptr: u64) -> Record<'_> {
-        Record::new_borrowed(&self.mmap[(ptr as usize)..], self.divider)
+        self.read_record(ptr)
             .expect("db corruption: dangling ptr")
+            .0
+    }
+
+    /// Reads the record at the given pointer, along with its encoded length.
+    fn read_record(&self, ptr: u64) -> anyhow::Result<(Record<'_>, usize)> {
+        let (segment, offset) = split(ptr);
+        let segment = self
+            .segments
+            .get(segment as usize)
+            .and_then(|s| s.as_deref())
+            .context("pointer into a dropped segment")?;
+        read_record(segment, offset, self.divider, &self.codec)
+    }
+
+    /// Reads a single record, except for the value of a data record. See [skim_record].
+    fn skim(&self, ptr: u64) -> anyhow::Result<(Skimmed<'_>, usize)> {
+        let (segment, offset) = split(ptr);
+        let segment = self
+            .segments
+            .get(segment as usize)
+            .and_then(|s| s.as_deref())
+            .context("pointer into a dropped segment")?;
+        skim_record(segment, offset, self.divider, &self.codec)
+    }
+
+    /// The pointer that the next record will be appended at, unless a new segment is started first.
+    fn ptr(&self) -> u64 {
+        join(self.segments.len() as u32 - 1, self.active().len())
+    }
+
+    /// The segment being appended to.
+    fn active(&self) -> &dyn Storage {
+        self.segments.last().unwrap().as_deref().unwrap()
+    }
+
+    fn active_mut(&mut self) -> &mut dyn Storage {
+        self.segments.last_mut().unwrap().as_deref_mut().unwrap()
+    }
+
+    /// Appends a record with the given closure, first starting a new segment if the active one is full. Returns the pointer to the record. If the closure fails, whatever it wrote is truncated away.
+    fn append<T>(
+        &mut self,
+        write: impl FnOnce(&mut dyn Write) -> std::io::Result<T>,
+    ) -> std::io::Result<(u64, T)> {
+        self.roll_over()?;
+        let id = self.segments.len() as u32 - 1;
+        let segment = self.active_mut();
+        let offset = segment.len();
+        let res = write(&mut Appender(segment)).and_then(|t| {
+            if segment.len() > 1 << SEGMENT_SHIFT {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::StorageFull,
+                    "segment is full",
+                ));
+            }
+            Ok(t)
+        });
+        match res {
+            Ok(t) => Ok((join(id, offset), t)),
+            Err(err) => {
+                segment.truncate(offset)?;
+                Err(err)
+            }
+        }
+    }
+
+    /// Starts a new segment if the active one is full.
+    fn roll_over(&mut self) -> std::io::Result<()> {
+        if let Some(dir) = &self.dir
+            && self.active().len() >= dir.segment_size
+        {
+            let id = self.segments.len() as u32;
+            let mut segment = dir.open(id)?;
+            init_segment(segment.as_mut(), self.divider, &self.codec)?;
+            // roots in the new segment will point into the old one, which must be durable first
+            self.active().sync()?;
+            self.segments.push(Some(segment));
+        }
+        Ok(())
     }
 
     /// Inserts a key. Does nothing if the key already exists
     pub fn insert(&mut self, key: [u8; 32], value: &[u8]) {
-        if self.lookup(key).is_none() {
-            // insert from root
-            self.root = self.insert_helper(
-                0,
-                self.root.clone(),
-                u128::from_le_bytes(*array_ref![&key, 0, 16]),
-                key,
-                value,
-            );
+        let leaf = RecordPtr::InMemory(Arc::new(Record::Data(key, value.to_vec().into())));
+        self.insert_leaves([(key, leaf)]);
+    }
+
+    /// Whether values are encrypted, which means that they cannot be streamed into the log.
+    pub fn encrypts(&self) -> bool {
+        self.codec.encrypts()
+    }
 
-            self.dirty = true;
+    /// Fails if a value of the given length could not be written with the current encoding. Such values must be rejected before they are inserted, or every later flush would fail.
+    pub fn check_value_len(&self, len: u64) -> std::io::Result<()> {
+        self.codec.check_value_len(len)
+    }
+
+    /// Reserves room at the end of the log for a large data record whose value, exactly `length` bytes long, is then written piece by piece with [Table::write_stream]. Returns None if the key already exists.
+    ///
+    /// Until [Table::finish_stream] writes the start of the record, the room reads as garbage, so recovery and scans skip over it, and other records can be appended after it in the meantime.
+    pub fn reserve_stream(
+        &mut self,
+        key: [u8; 32],
+        length: u64,
+    ) -> std::io::Result<Option<StreamReservation>> {
+        if self.lookup(key).is_some() {
+            return Ok(None);
+        }
+        let stream = DataStream::new(self.divider, key, length);
+        self.roll_over()?;
+        let id = self.segments.len() as u32 - 1;
+        let offset = self.active().len();
+        if offset + stream.encoded_len() > 1 << SEGMENT_SHIFT {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::StorageFull,
+                "segment is full",
+            ));
+        }
+        self.active_mut().extend(stream.encoded_len())?;
+        let ptr = join(id, offset);
+        self.streaming.push((ptr, stream.encoded_len()));
+        Ok(Some(StreamReservation { ptr, key, stream }))
+    }
+
+    /// The position of the earliest record whose value is still being streamed in, if any. Its room reads as garbage until it is finished, so feeds of the log must not move past it.
+    pub fn streaming_from(&self) -> Option<u64> {
+        self.streaming.iter().map(|&(ptr, _)| ptr).min()
+    }
+
+    /// Writes the next piece of a streamed value into its reserved room.
+    pub fn write_stream(
+        &mut self,
+        reservation: &mut StreamReservation,
+        piece: &[u8],
+    ) -> std::io::Result<()> {
+        let (id, offset) = split(reservation.ptr);
+        let offset = offset + DataStream::VALUE_OFFSET + reservation.stream.written();
+        reservation.stream.update(piece)?;
+        self.segment_mut(id)?.write_at(offset, piece)
+    }
+
+    /// Completes a streamed record by writing its start, then, if there is a `check`, reads the value back and passes it in before making the key visible. If anything fails, the record is abandoned.
+    pub fn finish_stream(
+        &mut self,
+        reservation: StreamReservation,
+        check: Option<impl FnOnce(&[u8]) -> std::io::Result<()>>,
+    ) -> std::io::Result<()> {
+        let (id, offset) = split(reservation.ptr);
+        let res = reservation
+            .stream
+            .finish()
+            .and_then(|header| self.segment_mut(id)?.write_at(offset, &header))
+            .and_then(|_| match check {
+                Some(check) => match self.load_record(reservation.ptr) {
+                    Record::Data(_, v) => check(&v),
+                    _ => unreachable!(),
+                },
+                None => Ok(()),
+            });
+        if let Err(err) = res {
+            self.abandon_stream(reservation)?;
+            return Err(err);
+        }
+        self.streaming.retain(|(p, _)| *p != reservation.ptr);
+        self.insert_leaves([(reservation.key, RecordPtr::OnDisk(reservation.ptr))]);
+        Ok(())
+    }
+
+    /// Gives up on a streamed record. If nothing was appended after it, its room is truncated away; otherwise it is left as garbage, with its start erased.
+    pub fn abandon_stream(&mut self, reservation: StreamReservation) -> std::io::Result<()> {
+        self.streaming.retain(|(p, _)| *p != reservation.ptr);
+        let (id, offset) = split(reservation.ptr);
+        let Ok(segment) = self.segment_mut(id) else {
+            return Ok(());
+        };
+        if segment.len() == offset + reservation.stream.encoded_len() {
+            segment.truncate(offset)
+        } else {
+            segment.write_at(offset, &[0u8; DataStream::VALUE_OFFSET as usize])
+        }
+    }
+
+    /// The segment with the given number, unless it was dropped.
+    fn segment_mut(&mut self, id: u32) -> std::io::Result<&mut dyn Storage> {
+        self.segments
+            .get_mut(id as usize)
+            .and_then(|s| s.as_deref_mut())
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "segment was dropped"))
+    }
+
+    /// Inserts a batch of keys, each pointing to an already-constructed data record, skipping keys that already exist. The batch is inserted as a unit: a flushed root contains either all of it or none of it.
+    pub fn insert_leaves(
+        &mut self,
+        leaves: impl IntoIterator<Item = ([u8; 32], RecordPtr<'static>)>,
+    ) {
+        for (key, leaf) in leaves {
+            if self.lookup(key).is_none() {
+                // insert from root
+                self.root = self.insert_into(self.root.clone(), key, leaf);
+                self.dirty = true;
+                self.notify(key);
+            }
+        }
+
+        // Flush when the pointer has moved at least 10MB since the last flush
+        // (starting a new segment jumps the pointer forward, which also triggers a flush)
+        if (self.ptr() - self.last_flush_ptr) >= MAX_FLUSH_INTERVAL {
+            self.flush(false);
+            self.last_flush_ptr = self.ptr();
+        }
+    }
+
+    /// Sends a signal down the channel once the key is inserted, or right away if it already exists. The wait is forgotten once `alive` is dead.
+    pub fn watch(&mut self, key: [u8; 32], tx: Sender<()>, alive: Weak<()>) {
+        // waits that were given up on are only noticed here
+        self.watchers.retain(|_, waits| {
+            waits.retain(|(alive, _)| alive.strong_count() > 0);
+            !waits.is_empty()
+        });
+        if self.lookup(key).is_some() {
+            let _ = tx.send(());
+        } else {
+            self.watchers.entry(key).or_default().push((alive, tx));
+        }
+    }
+
+    /// Sends every key inserted from now on down the channel, until its receiver is dropped.
+    pub fn subscribe(&mut self, tx: Sender<[u8; 32]>) {
+        self.subscribers.push(tx);
+    }
+
+    /// Tells watchers and subscribers about a newly inserted key.
+    fn notify(&mut self, key: [u8; 32]) {
+        for (_, tx) in self.watchers.remove(&key).into_iter().flatten() {
+            let _ = tx.send(());
+        }
+        self.subscribers.retain(|tx| tx.send(key).is_ok());
+    }
+
+    /// Inserts a key, pointing to the given data record, into an arbitrary root, returning the new root. Does not check whether the key already exists.
+    pub fn insert_into(
+        &self,
+        root: Record<'static>,
+        key: [u8; 32],
+        leaf: RecordPtr<'static>,
+    ) -> Record<'static> {
+        self.insert_helper(0, root, u128::from_le_bytes(*array_ref![&key, 0, 16]), leaf)
+    }
+
+    /// Removes a key from the current root, so that it is unreachable from every later root. Returns whether the key existed. The data record itself stays in the log until [Table::purge].
+    pub fn remove(&mut self, key: [u8; 32]) -> bool {
+        let ikey = u128::from_le_bytes(*array_ref![&key, 0, 16]);
+        match self.remove_helper(self.root.clone(), ikey, key) {
+            Removal::NotFound => false,
+            Removal::Node(root) => {
+                self.root = root;
+                self.dirty = true;
+                true
+            }
+            Removal::Leaf(_) | Removal::Empty => unreachable!("the root never collapses"),
+        }
+    }
+
+    fn remove_helper(&self, hamt: Record<'static>, ikey: u128, key: [u8; 32]) -> Removal {
+        let Record::HamtNode(r, mut bitmap, mut ptrs) = hamt else {
+            panic!("cannot remove from a data record")
+        };
+        let hindex = (ikey & 0b111111) as u32;
+        if (bitmap >> hindex) & 1 == 0 {
+            return Removal::NotFound;
+        }
+        let idx = (bitmap & ((1 << hindex) - 1)).count_ones() as usize;
+        let p = ptrs[idx].clone();
+        match RecordPtr::load(&p, |p| self.load_record(p)) {
+            Record::Data(existing_k, _) => {
+                if existing_k != key {
+                    return Removal::NotFound;
+                }
+                bitmap &= !(1 << hindex);
+                ptrs.remove(idx);
+            }
+            node => match self.remove_helper(node.into_owned(), ikey >> 6, key) {
+                Removal::NotFound => return Removal::NotFound,
+                Removal::Node(n) => ptrs[idx] = RecordPtr::InMemory(Arc::new(n)),
+                Removal::Leaf(l) => ptrs[idx] = l,
+                Removal::Empty => {
+                    bitmap &= !(1 << hindex);
+                    ptrs.remove(idx);
+                }
+            },
+        }
+        // keep the HAMT in the same shape that inserting the remaining keys would give: an interior node left with a single data record collapses into it
+        if !r {
+            if ptrs.is_empty() {
+                return Removal::Empty;
+            }
+            if ptrs.len() == 1
+                && matches!(
+                    RecordPtr::load(&ptrs[0], |p| self.load_record(p)),
+                    Record::Data(..)
+                )
+            {
+                return Removal::Leaf(ptrs.pop().unwrap());
+            }
+        }
+        Removal::Node(Record::HamtNode(r, bitmap, ptrs))
+    }
 
-            // Flush when the pointer has moved at least 10MB since the last flush
-            if (self.ptr - self.last_flush_ptr) >= MAX_FLUSH_INTERVAL {
-                self.flush(false);
-                self.last_flush_ptr = self.ptr;
+    /// Physically erases every data record in the log whose key is no longer reachable from the current root, nor from the snapshot of an open transaction, by overwriting it with zeros. The current root is flushed first, so older roots that still point to erased records are never needed for recovery. Returns how many records were erased.
+    pub fn purge(&mut self) -> std::io::Result<u64> {
+        self.flush(true);
+        let snapshots = self
+            .snapshots
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect_vec();
+        let mut dead = vec![];
+        for (offset, len, record) in self.scan(4096) {
+            if let Record::Data(key, _) = record
+                && self.lookup(key).is_none()
+                && snapshots.iter().all(|s| self.lookup_in(s, key).is_none())
+            {
+                dead.push((offset, len));
             }
         }
+        let zeros = vec![0u8; 65536];
+        for &(ptr, len) in dead.iter() {
+            let (segment, mut offset) = split(ptr);
+            let segment = self.segments[segment as usize].as_mut().unwrap();
+            let mut remaining = len;
+            while remaining > 0 {
+                let n = remaining.min(zeros.len());
+                segment.write_at(offset, &zeros[..n])?;
+                offset += n as u64;
+                remaining -= n;
+            }
+        }
+        for segment in self.segments.iter().flatten() {
+            segment.sync()?;
+        }
+        Ok(dead.len() as u64)
+    }
+
+    /// Flushes, then releases every byte range of the file that is unreachable from the current root back to the filesystem by punching holes, making the file sparse. Live records are not moved, so no offsets change. Returns the total length of the unreachable ranges.
+    ///
+    /// Older roots are destroyed too, so recovery can no longer fall back to them.
+    pub fn punch_garbage(&mut self) -> std::io::Result<u64> {
+        self.flush(true);
+        let mut live = vec![];
+        if let Some(root_ptr) = self.root_ptr {
+            self.live_records(root_ptr, &mut live);
+        }
+        // open transactions can still reach records the current root no longer does
+        for snapshot in self.snapshots.iter().filter_map(Weak::upgrade) {
+            self.live_in_memory(&snapshot, &mut live);
+        }
+        // records still being streamed in are not reachable yet
+        live.extend(self.streaming.iter().map(|&(ptr, len)| (ptr, len as usize)));
+        // the end of every segment closes off its last unreachable range
+        for (id, segment) in self.segments.iter().enumerate() {
+            if let Some(segment) = segment {
+                live.push((join(id as u32, segment.len()), 0));
+            }
+        }
+        live.sort_unstable();
+        let mut garbage = vec![];
+        let mut current = None;
+        let mut posn = 4096;
+        for (ptr, len) in live {
+            let (segment, offset) = split(ptr);
+            if current != Some(segment) {
+                current = Some(segment);
+                posn = 4096;
+            }
+            if offset > posn {
+                garbage.push((segment, posn, offset - posn));
+            }
+            posn = posn.max(offset + len as u64);
+        }
+        for &(segment, offset, len) in garbage.iter() {
+            self.segments[segment as usize]
+                .as_mut()
+                .unwrap()
+                .punch_hole(offset, len)?;
+        }
+        for segment in self.segments.iter().flatten() {
+            segment.sync()?;
+        }
+        Ok(garbage.iter().map(|(_, _, len)| len).sum())
+    }
+
+    /// Collects the pointers to and lengths of the on-disk record at the given pointer and everything below it.
+    fn live_records(&self, ptr: u64, out: &mut Vec<(u64, usize)>) {
+        let (skimmed, len) = self.skim(ptr).expect("db corruption: dangling ptr");
+        out.push((ptr, len));
+        if let Skimmed::Node(Record::HamtNode(_, _, ptrs)) = skimmed {
+            for p in ptrs {
+                if let RecordPtr::OnDisk(p) = p {
+                    self.live_records(p, out);
+                }
+            }
+        }
+    }
+
+    /// Collects the pointers to and lengths of the on-disk records below an in-memory record.
+    fn live_in_memory(&self, record: &Record<'_>, out: &mut Vec<(u64, usize)>) {
+        if let Record::HamtNode(_, _, ptrs) = record {
+            for p in ptrs {
+                match p {
+                    RecordPtr::OnDisk(p) => self.live_records(*p, out),
+                    RecordPtr::InMemory(r) => self.live_in_memory(r, out),
+                }
+            }
+        }
+    }
+
+    /// Scans the log sequentially, starting at the given pointer, yielding every well-formed record with its pointer and encoded length. Garbage, such as a torn write or an erased record, is skipped by searching for the next divider.
+    pub fn scan(&self, from: u64) -> impl Iterator<Item = (u64, usize, Record<'_>)> + '_ {
+        let (first, from) = split(from);
+        self.segments
+            .iter()
+            .enumerate()
+            .skip(first as usize)
+            .filter_map(|(id, segment)| Some((id as u32, segment.as_ref()?)))
+            .flat_map(move |(id, segment)| {
+                let from = if id == first { from } else { 0 };
+                scan_segment(segment.as_ref(), self.divider, &self.codec, from)
+                    .map(move |(offset, len, record)| (join(id, offset), len, record))
+            })
+    }
+
+    /// Lists the numbers of the segments of the log, oldest first.
+    pub fn segment_ids(&self) -> Vec<u32> {
+        (0..self.segments.len() as u32)
+            .filter(|id| self.segments[*id as usize].is_some())
+            .collect()
+    }
+
+    /// Drops a segment other than the one being appended to. Every record still reachable from the current root that lives in the segment is first copied to the end of the log, and the new root is flushed; the segment's file is then deleted. Values borrowed from the segment stay readable until the table is dropped, but older roots that point into it can no longer be used. Fails while an open transaction can still reach records in the segment.
+    pub fn drop_segment(&mut self, id: u32) -> std::io::Result<()> {
+        if self.dir.is_none() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "the log is not segmented",
+            ));
+        }
+        if id as usize + 1 >= self.segments.len() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "cannot drop the segment being appended to",
+            ));
+        }
+        if self.segments[id as usize].is_none() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "segment already dropped",
+            ));
+        }
+        // snapshots cannot be moved along with the current root
+        if self
+            .snapshots
+            .iter()
+            .filter_map(Weak::upgrade)
+            .any(|snapshot| self.reaches_segment(&snapshot, id))
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::ResourceBusy,
+                "an open transaction still sees records in the segment",
+            ));
+        }
+        if let Some(root) = self.evacuate(self.root.clone(), id)? {
+            self.root = root;
+        }
+        // the old root may itself live in the segment
+        self.dirty = true;
+        self.flush(true);
+        for segment in self.segments.iter().flatten() {
+            segment.sync()?;
+        }
+        // values borrowed earlier may still point into the storage, so it stays around, and its space is only released when it is closed
+        let segment = self.segments[id as usize].take().unwrap();
+        self.dir.as_ref().unwrap().remove(id)?;
+        self.graveyard.push(segment);
+        Ok(())
+    }
+
+    /// Whether any record below an in-memory record lives in the given segment. Values are never read.
+    fn reaches_segment(&self, record: &Record<'_>, id: u32) -> bool {
+        let Record::HamtNode(_, _, ptrs) = record else {
+            return false;
+        };
+        ptrs.iter().any(|ptr| match ptr {
+            RecordPtr::InMemory(child) => self.reaches_segment(child, id),
+            RecordPtr::OnDisk(p) => {
+                if split(*p).0 == id {
+                    return true;
+                }
+                match self.skim(*p).expect("db corruption: dangling ptr").0 {
+                    Skimmed::Node(node) => self.reaches_segment(&node, id),
+                    Skimmed::Data(_) => false,
+                }
+            }
+        })
+    }
+
+    /// Copies every record below a HAMT node that lives in the given segment to the end of the log. Returns the updated node, if anything below it moved.
+    fn evacuate(
+        &mut self,
+        node: Record<'static>,
+        segment: u32,
+    ) -> std::io::Result<Option<Record<'static>>> {
+        let Record::HamtNode(r, bitmap, mut ptrs) = node else {
+            return Ok(None);
+        };
+        let mut changed = false;
+        for ptr in ptrs.iter_mut() {
+            let (child, moving) = match ptr {
+                RecordPtr::InMemory(child) => ((**child).clone(), false),
+                RecordPtr::OnDisk(p) => {
+                    let moving = split(*p).0 == segment;
+                    let child = self.load_record(*p);
+                    if !moving && matches!(child, Record::Data(..)) {
+                        continue;
+                    }
+                    (child.into_owned(), moving)
+                }
+            };
+            match child {
+                Record::Data(..) => {
+                    if moving {
+                        let (divider, codec) = (self.divider, self.codec.clone());
+                        *ptr = RecordPtr::OnDisk(
+                            self.append(|w| child.write_bytes(divider, &codec, w))?.0,
+                        );
+                        changed = true;
+                    }
+                }
+                node => match self.evacuate(node.clone(), segment)? {
+                    Some(node) => {
+                        *ptr = RecordPtr::InMemory(Arc::new(node));
+                        changed = true;
+                    }
+                    None if moving => {
+                        *ptr = RecordPtr::InMemory(Arc::new(node));
+                        changed = true;
+                    }
+                    None => {}
+                },
+            }
+        }
+        Ok(changed.then_some(Record::HamtNode(r, bitmap, ptrs)))
     }
 
     /// Flushes everything to disk. The caller specifies whether or not to actually fully fsync
     pub fn flush(&mut self, fsync: bool) {
         if self.dirty {
-            let (_, new_root) = self.flush_helper(self.root.clone());
-            self.writer.flush().expect("flush failed");
+            let (root_ptr, new_root) = self.flush_helper(self.root.clone());
             if fsync {
-                self.writer.sync_all().expect("fs fail");
+                self.active().sync().expect("fs fail");
             }
             self.dirty = false;
             self.root = new_root;
+            self.root_ptr = Some(root_ptr);
         }
     }
 
@@ -193,67 +1274,244 @@ impl Table {
             ),
             p => p,
         };
-        let curr_posn = self.ptr;
-        let n = ptr
-            .write_bytes(self.divider, &mut self.writer)
+        let (divider, codec) = (self.divider, self.codec.clone());
+        if ptr.is_root()
+            && let Some(root_key) = codec.root_key()
+        {
+            let prev = self.last_auth;
+            let merkle = self.merkle_hash(&ptr);
+            let (curr_posn, (_, mac)) = self
+                .append(|w| ptr.write_authenticated_root(divider, root_key, prev, &merkle, w))
+                .expect("fs fail");
+            self.last_auth = Some((curr_posn, mac));
+            return (curr_posn, ptr);
+        }
+        let (curr_posn, _) = self
+            .append(|w| ptr.write_bytes(divider, &codec, w))
             .expect("fs fail");
-        self.ptr += n as u64;
         (curr_posn, ptr)
     }
 
     fn insert_helper(
-        &mut self,
+        &self,
         depth: usize,
         hamt: Record<'static>,
         ikey: u128,
-        key: [u8; 32],
-        value: &[u8],
+        leaf: RecordPtr<'static>,
     ) -> Record<'static> {
-        match hamt {
-            Record::Data(existing_k, existing_v) => {
-                let a =
-                    self.insert_helper(depth, Record::HamtNode(false, 0, vec![]), ikey, key, value);
-                let existing_ikey = u128::from_le_bytes(*array_ref![&existing_k, 0, 16]);
-                self.insert_helper(
-                    depth,
-                    a,
-                    existing_ikey >> (6 * depth),
-                    existing_k,
-                    &existing_v,
-                )
-            }
-            Record::HamtNode(r, mut bitmap, mut ptrs) => {
-                let hindex = (ikey & 0b111111) as u32;
-                // eprintln!("depth={depth}, hindex={hindex}, bitmap={:b}", bitmap);
-                if (bitmap >> hindex) & 1 == 1 {
-                    let idx = (bitmap & ((1 << hindex) - 1)).count_ones();
-                    let p = ptrs[idx as usize].clone();
-                    let ptr = p.load(|p| self.load_record(p).into_owned());
-                    // recurse down
-                    let c = self.insert_helper(depth + 1, ptr, ikey >> 6, key, value);
-                    ptrs[idx as usize] = RecordPtr::InMemory(Arc::new(c));
-                } else {
-                    // nothing here. this means we need to expand
-                    bitmap |= 1 << hindex;
-                    let idx = (bitmap & ((1 << hindex) - 1)).count_ones();
-                    log::trace!("depth={depth} idx={idx}");
-                    let record = Record::Data(key, value.to_vec().into());
-                    // let (addr, _) = self.flush_helper(record);
-                    ptrs.insert(idx as usize, RecordPtr::InMemory(Arc::new(record)));
+        let Record::HamtNode(r, mut bitmap, mut ptrs) = hamt else {
+            panic!("cannot insert into a data record")
+        };
+        let hindex = (ikey & 0b111111) as u32;
+        let idx = (bitmap & ((1 << hindex) - 1)).count_ones();
+        // eprintln!("depth={depth}, hindex={hindex}, bitmap={:b}", bitmap);
+        if (bitmap >> hindex) & 1 == 1 {
+            let p = ptrs[idx as usize].clone();
+            let child = match RecordPtr::load(&p, |p| self.load_record(p)) {
+                Record::Data(existing_k, _) => {
+                    // a data record is in the way, so we push it one level down into a fresh interior node, without copying it
+                    let existing_ikey = u128::from_le_bytes(*array_ref![&existing_k, 0, 16]);
+                    self.insert_helper(
+                        depth + 1,
+                        Record::HamtNode(false, 0, vec![]),
+                        existing_ikey >> (6 * (depth + 1)),
+                        p,
+                    )
                 }
-                Record::HamtNode(r, bitmap, ptrs)
+                node => node.into_owned(),
+            };
+            // recurse down
+            let c = self.insert_helper(depth + 1, child, ikey >> 6, leaf);
+            ptrs[idx as usize] = RecordPtr::InMemory(Arc::new(c));
+        } else {
+            // nothing here. this means we need to expand
+            bitmap |= 1 << hindex;
+            log::trace!("depth={depth} idx={idx}");
+            ptrs.insert(idx as usize, leaf);
+        }
+        Record::HamtNode(r, bitmap, ptrs)
+    }
+}
+
+/// Returns the child in the given slot of a HAMT node, if there is one.
+fn slot<'p, 'r>(bitmap: u64, ptrs: &'p [RecordPtr<'r>], hindex: u32) -> Option<&'p RecordPtr<'r>> {
+    ((bitmap >> hindex) & 1 == 1)
+        .then(|| &ptrs[(bitmap & ((1 << hindex) - 1)).count_ones() as usize])
+}
+
+/// Reads the record at the given offset of a segment, along with its encoded length.
+fn read_record<'s>(
+    segment: &'s dyn Storage,
+    offset: u64,
+    divider: u128,
+    codec: &Codec,
+) -> anyhow::Result<(Record<'s>, usize)> {
+    match read_raw(segment, offset, divider)? {
+        Cow::Borrowed(bytes) => Record::parse(bytes, divider, codec),
+        Cow::Owned(bytes) => {
+            let (record, len) = Record::parse(&bytes, divider, codec)?;
+            Ok((record.into_owned(), len))
+        }
+    }
+}
+
+/// A record read by [skim_record].
+enum Skimmed<'s> {
+    /// A data record, of which only the key was read
+    Data([u8; 32]),
+    /// A HAMT node, read whole
+    Node(Record<'s>),
+}
+
+/// Reads the record at the given offset of a segment, and its encoded length, except for the value of a data record of any kind, which is never read or decoded.
+fn skim_record<'s>(
+    segment: &'s dyn Storage,
+    offset: u64,
+    divider: u128,
+    codec: &Codec,
+) -> anyhow::Result<(Skimmed<'s>, usize)> {
+    let available = segment.len().saturating_sub(offset);
+    let head = segment.read_at(offset, available.min(MAX_PREFIX_LEN as u64 + 32) as usize)?;
+    if let Some(key) = Record::data_key(&head, divider)? {
+        return Ok((Skimmed::Data(key), Record::encoded_len(&head, divider)?));
+    }
+    let (record, len) = read_record(segment, offset, divider, codec)?;
+    Ok((Skimmed::Node(record), len))
+}
+
+/// A record read by [peek_record].
+enum Peeked<'s> {
+    /// A plain data record, whose value was not read, given by its key, the offset of its value within the segment and the length of its value
+    Plain([u8; 32], u64, u64),
+    /// Any other record, read whole
+    Record(Record<'s>),
+}
+
+/// Reads the record at the given offset of a segment, except for the value of a plain data record.
+fn peek_record<'s>(
+    segment: &'s dyn Storage,
+    offset: u64,
+    divider: u128,
+    codec: &Codec,
+) -> anyhow::Result<Peeked<'s>> {
+    let available = segment.len().saturating_sub(offset);
+    let prefix = segment.read_at(offset, available.min(4096) as usize)?;
+    let head = &prefix[..prefix.len().min(MAX_PREFIX_LEN + 32)];
+    if let Some((key, start, len)) = Record::plain_data(head, divider)? {
+        return Ok(Peeked::Plain(key, offset + start as u64, len as u64));
+    }
+    let len = Record::encoded_len(head, divider)?;
+    let bytes = if len <= prefix.len() {
+        prefix
+    } else {
+        segment.read_at(offset, len)?
+    };
+    Ok(Peeked::Record(match bytes {
+        Cow::Borrowed(bytes) => Record::parse(bytes, divider, codec)?.0,
+        Cow::Owned(bytes) => Record::parse(&bytes, divider, codec)?.0.into_owned(),
+    }))
+}
+
+/// Reads the bytes of the whole record at the given offset of a segment, without parsing it.
+fn read_raw(segment: &dyn Storage, offset: u64, divider: u128) -> anyhow::Result<Cow<'_, [u8]>> {
+    // most records are small enough to read in one go
+    let available = segment.len().saturating_sub(offset);
+    let prefix = segment.read_at(offset, available.min(4096) as usize)?;
+    let len = Record::encoded_len(&prefix[..prefix.len().min(MAX_PREFIX_LEN)], divider)?;
+    Ok(if len <= prefix.len() {
+        prefix
+    } else {
+        segment.read_at(offset, len)?
+    })
+}
+
+/// Scans a single segment sequentially. See [Table::scan].
+fn scan_segment<'s>(
+    segment: &'s dyn Storage,
+    divider: u128,
+    codec: &'s Codec,
+    from: u64,
+) -> impl Iterator<Item = (u64, usize, Record<'s>)> + 's {
+    let end = segment.len();
+    let mut posn = from.max(4096);
+    std::iter::from_fn(move || {
+        while posn + 32 <= end {
+            match read_record(segment, posn, divider, codec) {
+                Ok((record, len)) => {
+                    let offset = posn;
+                    posn += len as u64;
+                    return Some((offset, len, record));
+                }
+                Err(_) => posn = find_divider(segment, posn + 1, divider).unwrap_or(end),
             }
         }
+        None
+    })
+}
+
+/// Finds the next occurrence of the divider in a segment, at or after the given offset.
+fn find_divider(segment: &dyn Storage, mut from: u64, divider: u128) -> Option<u64> {
+    const WINDOW: u64 = 1 << 20;
+    let end = segment.len();
+    while from + 16 <= end {
+        let len = (end - from).min(WINDOW);
+        let window = segment.read_at(from, len as usize).ok()?;
+        if let Some(p) = window.windows(16).position(|w| w == divider.to_le_bytes()) {
+            return Some(from + p as u64);
+        }
+        if len < WINDOW {
+            break;
+        }
+        // windows overlap, so a divider straddling two of them is not missed
+        from += len - 15;
     }
+    None
+}
+
+/// Writes the reserved region to a segment that does not have one yet.
+fn init_segment(segment: &mut dyn Storage, divider: u128, codec: &Codec) -> std::io::Result<()> {
+    if segment.len() < 4096 {
+        segment.truncate(0)?;
+        segment.append(&reserved_region(divider, codec))?;
+    }
+    Ok(())
+}
+
+/// Appends to a segment through [Write].
+struct Appender<'a>(&'a mut dyn Storage);
+
+impl Write for Appender<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.append(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The outcome of removing a key from a HAMT node.
+enum Removal {
+    /// The key was not there.
+    NotFound,
+    /// The node, with the key removed.
+    Node(Record<'static>),
+    /// The node collapsed into its only remaining data record.
+    Leaf(RecordPtr<'static>),
+    /// The node has no children left.
+    Empty,
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::storage::MemoryStorage;
+
     use super::*;
 
     #[test]
     fn hamt_simple() {
-        let mut tab = Table::open(Path::new("/tmp/test_meshanina.db")).unwrap();
+        let mut tab = Table::new(Box::new(MemoryStorage::new())).unwrap();
         for ctr in 0u64..100 {
             let k = *blake3::hash(format!("key{}", ctr).as_bytes()).as_bytes();
             tab.insert(k, &ctr.to_le_bytes());
@@ -264,4 +1522,17 @@ mod tests {
             assert_eq!(array_ref![&b, 0, 8], &ctr.to_le_bytes());
         }
     }
+
+    #[test]
+    fn watch_forgets_dropped() {
+        let mut tab = Table::new(Box::new(MemoryStorage::new())).unwrap();
+        let (tx, _rx) = std::sync::mpsc::channel();
+        for ctr in 0u64..100 {
+            let alive = Arc::new(());
+            tab.watch([ctr as u8; 32], tx.clone(), Arc::downgrade(&alive));
+        }
+        let alive = Arc::new(());
+        tab.watch([0xff; 32], tx, Arc::downgrade(&alive));
+        assert_eq!(tab.watchers.len(), 1);
+    }
 }
@@ -0,0 +1,97 @@
+use std::{borrow::Cow, sync::Arc};
+
+use crate::{
+    Mapping,
+    record::{Record, RecordPtr},
+};
+
+/// A batch of inserts that become visible all at once.
+///
+/// Inserts are staged on a private copy of the root, so they are visible through [Transaction::get] but not through the [Mapping]. [Transaction::commit] publishes them atomically; dropping the transaction discards them. Nothing staged in a transaction ever lands in a flushed root until it is committed.
+pub struct Transaction<'a> {
+    mapping: &'a Mapping,
+    /// The root the transaction started from, which keeps its records from being punched out
+    _base: Arc<Record<'static>>,
+    root: Record<'static>,
+    staged: Vec<([u8; 32], RecordPtr<'static>)>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(mapping: &'a Mapping) -> Self {
+        let base = mapping.inner.write().snapshot();
+        Self {
+            mapping,
+            root: (*base).clone(),
+            _base: base,
+            staged: vec![],
+        }
+    }
+
+    /// Gets a key-value pair, as seen from within the transaction.
+    pub fn get(&self, key: [u8; 32]) -> Option<Cow<'_, [u8]>> {
+        let inner = self.mapping.inner.read();
+        let bts = inner.lookup_in(&self.root, key)?;
+        Some(unsafe { std::mem::transmute::<Cow<'_, [u8]>, Cow<'_, [u8]>>(bts) })
+    }
+
+    /// Stages a key-value pair. Fails like [Mapping::insert] does.
+    pub fn insert(&mut self, key: [u8; 32], value: &[u8]) -> std::io::Result<()> {
+        self.mapping.check_key(key, value)?;
+        let inner = self.mapping.inner.read();
+        inner.check_value_len(value.len() as u64)?;
+        if inner.lookup_in(&self.root, key).is_none() {
+            let leaf = RecordPtr::InMemory(Arc::new(Record::Data(key, value.to_vec().into())));
+            self.root = inner.insert_into(self.root.clone(), key, leaf.clone());
+            self.staged.push((key, leaf));
+        }
+        Ok(())
+    }
+
+    /// Publishes every staged insert at once.
+    pub fn commit(self) {
+        self.mapping.inner.write().insert_leaves(self.staged);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transaction_atomic() {
+        let dir = tempfile::tempdir().unwrap();
+        let fname = dir.path().join("tx.db");
+        let key = |ctr: u64| *blake3::hash(&ctr.to_le_bytes()).as_bytes();
+        {
+            let db = Mapping::open(&fname).unwrap();
+            db.insert(key(0), b"outside").unwrap();
+
+            let mut tx = db.transaction();
+            tx.insert(key(1), b"discarded").unwrap();
+            assert_eq!(&tx.get(key(0)).unwrap()[..], b"outside");
+            assert_eq!(&tx.get(key(1)).unwrap()[..], b"discarded");
+            assert!(db.get(key(1)).is_none());
+            drop(tx);
+            assert!(db.get(key(1)).is_none());
+
+            let mut tx = db.transaction();
+            for ctr in 2..100 {
+                tx.insert(key(ctr), &ctr.to_le_bytes()).unwrap();
+            }
+            let mut pending = db.transaction();
+            pending.insert(key(100), b"never committed").unwrap();
+            db.flush();
+            assert!(db.get(key(2)).is_none());
+            tx.commit();
+            for ctr in 2..100 {
+                assert_eq!(&db.get(key(ctr)).unwrap()[..], &ctr.to_le_bytes());
+            }
+            db.flush();
+        }
+        let db = Mapping::open(&fname).unwrap();
+        assert_eq!(&db.get(key(0)).unwrap()[..], b"outside");
+        assert!(db.get(key(1)).is_none());
+        assert_eq!(&db.get(key(99)).unwrap()[..], &99u64.to_le_bytes());
+        assert!(db.get(key(100)).is_none());
+    }
+}